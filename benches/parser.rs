@@ -0,0 +1,20 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use teleinfo2mqtt_rs::teleinfo::parser::{parse_teleinfo, validate_checksum};
+
+// A full historical frame as read from the serial port, STX/ETX and CR/LF included
+const FRAME: &str = "\x02\nADCO 012345678901 E\r\nOPTARIF BASE 0\r\nISOUSC 30 9\r\nBASE 002809718 .\r\nPTEC TH.. $\r\nIINST 002 Y\r\nIMAX 090 H\r\nPAPP 00390 -\r\nHHPHC A ,\r\nMOTDETAT 000000 B\r\x03";
+
+fn bench_parse_teleinfo(c: &mut Criterion) {
+    c.bench_function("parse_teleinfo", |b| {
+        b.iter(|| parse_teleinfo(black_box(FRAME), true).unwrap())
+    });
+}
+
+fn bench_validate_checksum(c: &mut Criterion) {
+    c.bench_function("validate_checksum", |b| {
+        b.iter(|| validate_checksum(black_box("ADCO 012345678901 E")))
+    });
+}
+
+criterion_group!(benches, bench_parse_teleinfo, bench_validate_checksum);
+criterion_main!(benches);
@@ -0,0 +1,280 @@
+use crate::log_limit::LogLimiter;
+use crate::teleinfo::parser::TeleinfoFrame;
+use serde_json::{Map, Value};
+use std::error::Error;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::broadcast;
+use tokio::time;
+use tracing::{event, instrument, Level};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const RETRY_BACKOFF_INITIAL: Duration = Duration::from_millis(500);
+// Number of frames waiting to be posted before the oldest are dropped, so that a slow
+// or unreachable Emoncms never holds up MQTT publishing
+const QUEUE_FRAMES: usize = 16;
+
+// Posts frames to an Emoncms /input/post endpoint, one input node per meter.
+// Only plain http is supported, like a local Emoncms install.
+#[derive(Debug)]
+pub struct Emoncms {
+    host: String,
+    port: u16,
+    path: String,
+    api_key: String,
+}
+
+impl Emoncms {
+    pub fn new(url: &str, api_key: String) -> Result<Self, String> {
+        let address = url.strip_prefix("http://").ok_or_else(|| {
+            format!(
+                "Unsupported Emoncms URL {:?}, only http:// is supported",
+                url
+            )
+        })?;
+        let (authority, base_path) = match address.find('/') {
+            Some(index) => address.split_at(index),
+            None => (address, ""),
+        };
+        let parse_port = |port: &str| {
+            port.parse::<u16>()
+                .map_err(|_| format!("Invalid port in Emoncms URL {:?}", url))
+        };
+        // An IPv6 address is bracketed, e.g. http://[::1]:8080
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(bracketed) => match bracketed.split_once(']') {
+                Some((host, "")) => (host, 80),
+                Some((host, port)) => match port.strip_prefix(':') {
+                    Some(port) => (host, parse_port(port)?),
+                    None => return Err(format!("Invalid host in Emoncms URL {:?}", url)),
+                },
+                None => return Err(format!("Invalid host in Emoncms URL {:?}", url)),
+            },
+            None => match authority.rsplit_once(':') {
+                Some((host, port)) => (host, parse_port(port)?),
+                None => (authority, 80),
+            },
+        };
+        if host.is_empty() {
+            return Err(format!("Missing host in Emoncms URL {:?}", url));
+        }
+
+        Ok(Emoncms {
+            host: host.to_string(),
+            port,
+            path: format!("{}/input/post", base_path.trim_end_matches('/')),
+            api_key,
+        })
+    }
+
+    // Posts the frames sent to the returned sender from a task of its own
+    pub fn spawn(self, retries: u32, log_interval: Duration) -> broadcast::Sender<TeleinfoFrame> {
+        let (sender, mut receiver) = broadcast::channel(QUEUE_FRAMES);
+
+        tokio::spawn(async move {
+            let mut error_log = LogLimiter::new(log_interval);
+            loop {
+                match receiver.recv().await {
+                    Ok(value) => match self.post(&value, retries).await {
+                        Ok(_) => error_log.reset(),
+                        Err(e) => {
+                            if let Some(suppressed) = error_log.check() {
+                                event!(Level::ERROR, error = ?e, suppressed, "Error while posting teleinfo frame to Emoncms");
+                            }
+                        }
+                    },
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        event!(
+                            Level::WARN,
+                            skipped,
+                            "Emoncms is too slow, dropped the oldest frames"
+                        );
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return,
+                }
+            }
+        });
+
+        sender
+    }
+
+    #[instrument(skip(self, value))]
+    async fn post(
+        &self,
+        value: &TeleinfoFrame,
+        retries: u32,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let body = self.request_body(value);
+        let mut backoff = RETRY_BACKOFF_INITIAL;
+        let mut attempt = 0;
+        loop {
+            let result = match time::timeout(REQUEST_TIMEOUT, self.send(&body)).await {
+                Ok(result) => result,
+                Err(_) => Err("Emoncms request timed out".into()),
+            };
+            match result {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt < retries => {
+                    attempt += 1;
+                    event!(
+                        Level::WARN,
+                        error = ?e,
+                        attempt,
+                        ?backoff,
+                        "Failed to post frame to Emoncms, retrying"
+                    );
+                    time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn send(&self, body: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut stream = TcpStream::connect((self.host.as_str(), self.port)).await?;
+        let host = if self.host.contains(':') {
+            format!("[{}]", self.host)
+        } else {
+            self.host.clone()
+        };
+        // HTTP/1.0 so that the response isn't chunked and its body can be read as is
+        let request = format!(
+            "POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: application/x-www-form-urlencoded\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.path,
+            host,
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes()).await?;
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        check_response(&response)?;
+
+        event!(Level::DEBUG, "Posted frame to Emoncms");
+        Ok(())
+    }
+
+    fn request_body(&self, value: &TeleinfoFrame) -> String {
+        format!(
+            "node={}&fulljson={}&apikey={}",
+            percent_encode(&value.adco),
+            percent_encode(&fulljson(value).to_string()),
+            percent_encode(&self.api_key)
+        )
+    }
+}
+
+// Emoncms answers 200 even when it rejects the input, e.g. for a wrong API key, so
+// the body tells whether it was accepted: "ok", or {"success":true} on newer versions
+fn check_response(response: &str) -> Result<(), String> {
+    let (head, body) = response.split_once("\r\n\r\n").unwrap_or((response, ""));
+    let status_line = head.lines().next().unwrap_or_default();
+    if !status_line.contains(" 200 ") {
+        return Err(format!("Emoncms answered {:?}", status_line));
+    }
+
+    let body = body.trim();
+    let accepted = body == "ok"
+        || serde_json::from_str::<Value>(body).is_ok_and(|json| json["success"] == true);
+    if !accepted {
+        return Err(format!("Emoncms rejected the frame: {:?}", body));
+    }
+    Ok(())
+}
+
+// Emoncms inputs are numeric, so only the fields holding a number are sent
+fn fulljson(value: &TeleinfoFrame) -> Value {
+    let inputs = [
+        ("ISOUSC", Some(value.isousc.as_str())),
+        ("BASE", value.base.as_deref()),
+        ("IINST", Some(value.iinst.as_str())),
+        ("IMAX", Some(value.imax.as_str())),
+        ("PAPP", Some(value.papp.as_str())),
+    ]
+    .into_iter()
+    .filter_map(|(key, field)| {
+        let number = field?.parse::<i64>().ok()?;
+        Some((key.to_string(), Value::from(number)))
+    })
+    .collect::<Map<_, _>>();
+
+    Value::Object(inputs)
+}
+
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::teleinfo::parser::parse_teleinfo;
+
+    #[test]
+    fn test_emoncms_url() {
+        let emoncms = Emoncms::new("http://emoncms.local", "key".to_string()).unwrap();
+        assert_eq!(emoncms.host, "emoncms.local");
+        assert_eq!(emoncms.port, 80);
+        assert_eq!(emoncms.path, "/input/post");
+
+        let emoncms = Emoncms::new("http://192.168.1.42:8080/emoncms/", "key".to_string()).unwrap();
+        assert_eq!(emoncms.host, "192.168.1.42");
+        assert_eq!(emoncms.port, 8080);
+        assert_eq!(emoncms.path, "/emoncms/input/post");
+
+        let emoncms = Emoncms::new("http://[::1]:8080/emoncms", "key".to_string()).unwrap();
+        assert_eq!(emoncms.host, "::1");
+        assert_eq!(emoncms.port, 8080);
+        assert_eq!(emoncms.path, "/emoncms/input/post");
+
+        let emoncms = Emoncms::new("http://[fe80::1]", "key".to_string()).unwrap();
+        assert_eq!(emoncms.host, "fe80::1");
+        assert_eq!(emoncms.port, 80);
+
+        assert!(Emoncms::new("http://[::1", "key".to_string()).is_err());
+        assert!(Emoncms::new("http://[::1]8080", "key".to_string()).is_err());
+        assert!(Emoncms::new("https://emoncms.org", "key".to_string()).is_err());
+        assert!(Emoncms::new("http://emoncms.local:http", "key".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_emoncms_request_body() {
+        let emoncms = Emoncms::new("http://emoncms.local", "a b".to_string()).unwrap();
+        let frame = parse_teleinfo("ADCO 012345678901 E\nOPTARIF BASE 0\nISOUSC 30 9\nBASE 002809718 .\nPTEC TH.. $\nIINST 002 Y\nIMAX 090 H\nPAPP 00390 -\nHHPHC A ,\nMOTDETAT 000000 B", true).unwrap();
+        assert_eq!(
+            fulljson(&frame).to_string(),
+            r#"{"ISOUSC":30,"BASE":2809718,"IINST":2,"IMAX":90,"PAPP":390}"#
+        );
+        assert_eq!(
+            emoncms.request_body(&frame),
+            "node=012345678901&fulljson=%7B%22ISOUSC%22%3A30%2C%22BASE%22%3A2809718%2C%22IINST%22%3A2%2C%22IMAX%22%3A90%2C%22PAPP%22%3A390%7D&apikey=a%20b"
+        );
+    }
+
+    #[test]
+    fn test_emoncms_check_response() {
+        assert!(check_response("HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").is_ok());
+        assert!(check_response("HTTP/1.1 200 OK\r\n\r\n{\"success\": true}").is_ok());
+        // A wrong API key or a rejected input is still answered with a 200
+        assert!(check_response(
+            "HTTP/1.1 200 OK\r\n\r\n{\"success\":false,\"message\":\"Username or password empty\"}"
+        )
+        .is_err());
+        assert!(check_response(
+            "HTTP/1.1 200 OK\r\n\r\nError: Format error, json string supplied is not valid"
+        )
+        .is_err());
+        assert!(check_response("HTTP/1.1 500 Internal Server Error\r\n\r\nok").is_err());
+    }
+}
@@ -0,0 +1,167 @@
+use serde_json::{json, Value};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{event, Level};
+
+pub const DEFAULT_MAX_BYTES: u64 = 10 * 1024 * 1024;
+pub const DEFAULT_KEEP: usize = 5;
+
+// Appends frames as JSON lines to a file, each with the time it was received since
+// historical frames carry no date. The file is rotated to <path>.1, <path>.2, ... once it
+// reaches its maximum size, keeping a fixed number of rotated files
+pub struct FrameLog {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_bytes: u64,
+    keep: usize,
+}
+
+impl FrameLog {
+    pub fn open(path: PathBuf, max_bytes: u64, keep: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        event!(Level::INFO, ?path, size, "Logging frames to file");
+
+        Ok(FrameLog {
+            path,
+            file,
+            size,
+            max_bytes,
+            keep,
+        })
+    }
+
+    pub fn write(&mut self, frame: Value, received_at: SystemTime) -> io::Result<()> {
+        let line = format!(
+            "{}\n",
+            json!({"received_at": rfc3339(received_at), "frame": frame})
+        );
+        if self.size > 0 && self.size + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+
+        // Files aren't buffered, so every line is handed to the OS right away
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.keep > 0 {
+            for index in (1..self.keep).rev() {
+                rename_if_exists(
+                    &rotated_path(&self.path, index),
+                    &rotated_path(&self.path, index + 1),
+                )?;
+            }
+            fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        }
+
+        self.file = File::create(&self.path)?;
+        self.size = 0;
+        event!(Level::DEBUG, path = ?self.path, "Rotated frame log");
+        Ok(())
+    }
+}
+
+// Formats a time as RFC 3339 in UTC with milliseconds, e.g. 2026-10-16T17:02:27.686Z
+fn rfc3339(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let (days, seconds_of_day) = ((seconds / 86_400) as i64, seconds % 86_400);
+
+    // Converts days since 1970-01-01 to a date in the proleptic Gregorian calendar,
+    // counting 400-year eras from 0000-03-01 so that leap days end a year
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        seconds_of_day / 3_600,
+        seconds_of_day / 60 % 60,
+        seconds_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{}", index));
+    PathBuf::from(rotated)
+}
+
+fn rename_if_exists(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+    use std::time::Duration;
+
+    fn line(frame: u32) -> String {
+        format!(
+            "{{\"received_at\":\"1970-01-01T00:00:00.000Z\",\"frame\":{}}}\n",
+            frame
+        )
+    }
+
+    #[test]
+    fn test_frame_log_rotation() {
+        let dir = env::temp_dir().join(format!("teleinfo-frame-log-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("frames.jsonl");
+
+        // Every line has the same size, and a file holds 2 lines
+        let max_bytes = 2 * line(1).len() as u64;
+        let mut frame_log = FrameLog::open(path.clone(), max_bytes, 2).unwrap();
+        for frame in 1..=7 {
+            frame_log.write(json!(frame), UNIX_EPOCH).unwrap();
+        }
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), line(7));
+        assert_eq!(
+            fs::read_to_string(rotated_path(&path, 1)).unwrap(),
+            line(5) + &line(6)
+        );
+        assert_eq!(
+            fs::read_to_string(rotated_path(&path, 2)).unwrap(),
+            line(3) + &line(4)
+        );
+        assert!(!rotated_path(&path, 3).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rfc3339() {
+        assert_eq!(rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        let leap_day = UNIX_EPOCH + Duration::from_millis(1_709_210_096_789);
+        assert_eq!(rfc3339(leap_day), "2024-02-29T12:34:56.789Z");
+        let new_year_eve = UNIX_EPOCH + Duration::from_secs(978_307_199);
+        assert_eq!(rfc3339(new_year_eve), "2000-12-31T23:59:59.000Z");
+    }
+}
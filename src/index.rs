@@ -0,0 +1,183 @@
+use crate::teleinfo::parser::TeleinfoFrame;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Instant;
+use tracing::{event, Level};
+
+// Energy indices only ever increase, anything more than this decrease (in Wh) is
+// either a corrupted frame or another meter
+const INDEX_DECREASE_TOLERANCE_WH: i64 = 1;
+// The largest subscription (36 kVA) bounds how fast the index can increase, a
+// bigger jump is a corrupted frame as well
+const MAX_POWER_W: f64 = 36_000.0;
+const INDEX_INCREASE_TOLERANCE_WH: i64 = 10;
+// Past this many implausible indices in a row, the reference itself was most likely
+// the corrupted one (e.g. the very first frame), so the new index is trusted instead
+const REBASELINE_AFTER_REJECTIONS: u32 = 10;
+
+// What to do with a BASE index that decreased or increased implausibly
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IndexPolicy {
+    Warn,
+    Drop,
+}
+
+impl FromStr for IndexPolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy.to_lowercase().as_str() {
+            "warn" => Ok(IndexPolicy::Warn),
+            "drop" => Ok(IndexPolicy::Drop),
+            _ => Err(format!("Unknown index spike policy {:?}", policy)),
+        }
+    }
+}
+
+struct MeterIndex {
+    base: i64,
+    received_at: Instant,
+    rejections: u32,
+}
+
+// Tracks the last plausible energy index of each meter, to keep a corrupted frame
+// from breaking Home Assistant's total_increasing statistics
+pub struct IndexMonitor {
+    policy: IndexPolicy,
+    indices: HashMap<String, MeterIndex>, // By ADCO
+}
+
+impl IndexMonitor {
+    pub fn new(policy: IndexPolicy) -> Self {
+        IndexMonitor {
+            policy,
+            indices: HashMap::new(),
+        }
+    }
+
+    // Returns whether the frame's BASE index should be kept
+    pub fn check(&mut self, frame: &TeleinfoFrame, now: Instant) -> bool {
+        let Some(Ok(base)) = frame.base.as_deref().map(str::parse::<i64>) else {
+            return true;
+        };
+        let accepted = MeterIndex {
+            base,
+            received_at: now,
+            rejections: 0,
+        };
+
+        let Some(last) = self.indices.get_mut(&frame.adco) else {
+            self.indices.insert(frame.adco.clone(), accepted);
+            return true;
+        };
+
+        let elapsed_hours = now.duration_since(last.received_at).as_secs_f64() / 3600.0;
+        let max_increase = (MAX_POWER_W * elapsed_hours) as i64 + INDEX_INCREASE_TOLERANCE_WH;
+        let change = base - last.base;
+        if change >= -INDEX_DECREASE_TOLERANCE_WH && change <= max_increase {
+            *last = accepted;
+            return true;
+        }
+
+        last.rejections += 1;
+        if last.rejections >= REBASELINE_AFTER_REJECTIONS {
+            event!(
+                Level::WARN,
+                last_base = last.base,
+                base,
+                rejections = last.rejections,
+                "BASE energy index kept disagreeing with the last one, using it as the new reference"
+            );
+            *last = accepted;
+            return true;
+        }
+
+        event!(
+            Level::WARN,
+            last_base = last.base,
+            base,
+            policy = ?self.policy,
+            "BASE energy index changed implausibly"
+        );
+        self.policy == IndexPolicy::Warn
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::teleinfo::parser::parse_teleinfo;
+    use std::time::Duration;
+
+    fn frame(adco: &str, base: &str) -> TeleinfoFrame {
+        let mut frame = parse_teleinfo("ADCO 012345678901 E\nOPTARIF BASE 0\nISOUSC 30 9\nBASE 002809718 .\nPTEC TH.. $\nIINST 002 Y\nIMAX 090 H\nPAPP 00390 -\nHHPHC A ,\nMOTDETAT 000000 B", true).unwrap();
+        frame.adco = adco.to_string();
+        frame.base = Some(base.to_string());
+        frame
+    }
+
+    #[test]
+    fn test_index_monitor_warn() {
+        let mut monitor = IndexMonitor::new(IndexPolicy::Warn);
+        let now = Instant::now();
+        assert!(monitor.check(&frame("012345678901", "002809718"), now));
+        assert!(monitor.check(&frame("012345678901", "000000042"), now));
+    }
+
+    #[test]
+    fn test_index_monitor_drop() {
+        let mut monitor = IndexMonitor::new(IndexPolicy::Drop);
+        let now = Instant::now();
+        assert!(monitor.check(&frame("012345678901", "002809718"), now));
+        assert!(monitor.check(&frame("012345678901", "002809718"), now));
+        assert!(!monitor.check(&frame("012345678901", "000000042"), now));
+        // The last valid index is kept as the reference
+        assert!(!monitor.check(&frame("012345678901", "002809700"), now));
+        assert!(monitor.check(&frame("012345678901", "002809719"), now));
+    }
+
+    #[test]
+    fn test_index_monitor_increase_spike() {
+        let mut monitor = IndexMonitor::new(IndexPolicy::Drop);
+        let now = Instant::now();
+        assert!(monitor.check(&frame("012345678901", "002809718"), now));
+        assert!(!monitor.check(
+            &frame("012345678901", "092809718"),
+            now + Duration::from_secs(1)
+        ));
+        // The spike didn't become the reference
+        assert!(monitor.check(
+            &frame("012345678901", "002809720"),
+            now + Duration::from_secs(2)
+        ));
+        // An hour at most 36 kW
+        assert!(monitor.check(
+            &frame("012345678901", "002839720"),
+            now + Duration::from_secs(3602)
+        ));
+    }
+
+    #[test]
+    fn test_index_monitor_rebaseline() {
+        let mut monitor = IndexMonitor::new(IndexPolicy::Drop);
+        let now = Instant::now();
+        // A corrupted first frame
+        assert!(monitor.check(&frame("012345678901", "092809718"), now));
+        for _ in 1..REBASELINE_AFTER_REJECTIONS {
+            assert!(!monitor.check(&frame("012345678901", "002809718"), now));
+        }
+        assert!(monitor.check(&frame("012345678901", "002809718"), now));
+        assert!(monitor.check(&frame("012345678901", "002809719"), now));
+    }
+
+    #[test]
+    fn test_index_monitor_other_meter() {
+        let mut monitor = IndexMonitor::new(IndexPolicy::Drop);
+        let now = Instant::now();
+        assert!(monitor.check(&frame("012345678901", "002809718"), now));
+        assert!(monitor.check(&frame("012345678902", "000000042"), now));
+        // Each meter keeps its own reference
+        assert!(monitor.check(&frame("012345678901", "002809719"), now));
+        assert!(!monitor.check(&frame("012345678902", "000000010"), now));
+    }
+}
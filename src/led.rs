@@ -0,0 +1,80 @@
+use rppal::gpio::{self, Gpio, OutputPin};
+use std::error::Error;
+use std::thread;
+use std::time::Duration;
+use tracing::{event, Level};
+
+const GPIO_PITINFO_GREEN_LED: u8 = 4;
+const DEFAULT_GPIO_CHIP: &str = "gpiochip0";
+
+// The PiTInfo green LED, pulsed on every published frame
+pub struct Led {
+    pin: OutputPin,
+}
+
+impl Led {
+    // rppal always drives the SoC's own GPIO controller and can't be pointed at
+    // another chip, so anything else is refused rather than driving a wrong pin
+    pub fn new(chip: Option<&str>) -> Result<Self, Box<dyn Error>> {
+        let chip = chip.unwrap_or(DEFAULT_GPIO_CHIP);
+        if !is_default_chip(chip) {
+            return Err(format!(
+                "GPIO chip {:?} is not supported, only the SoC's GPIO controller ({}) can be used",
+                chip, DEFAULT_GPIO_CHIP
+            )
+            .into());
+        }
+
+        let gpio = match Gpio::new() {
+            Ok(gpio) => gpio,
+            Err(gpio::Error::PermissionDenied(path)) => {
+                return Err(format!(
+                    "Permission denied on {}, run as a user in the gpio group",
+                    path
+                )
+                .into())
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let pin = gpio.get(GPIO_PITINFO_GREEN_LED)?.into_output();
+        event!(
+            Level::INFO,
+            chip,
+            pin = GPIO_PITINFO_GREEN_LED,
+            "Using GPIO pin for the LED"
+        );
+        Ok(Led { pin })
+    }
+
+    pub fn pulse(&mut self) {
+        self.pin.set_high();
+        thread::sleep(Duration::from_millis(10));
+        self.pin.set_low();
+    }
+}
+
+// rppal only restores the pin's mode when it's dropped, drive it low first so the LED
+// isn't left lit when exiting mid-pulse
+impl Drop for Led {
+    fn drop(&mut self) {
+        self.pin.set_low();
+    }
+}
+
+fn is_default_chip(chip: &str) -> bool {
+    let chip = chip.strip_prefix("/dev/").unwrap_or(chip);
+    chip == DEFAULT_GPIO_CHIP || chip == "0"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_default_chip() {
+        assert!(is_default_chip("gpiochip0"));
+        assert!(is_default_chip("/dev/gpiochip0"));
+        assert!(is_default_chip("0"));
+        assert!(!is_default_chip("gpiochip4"));
+    }
+}
@@ -0,0 +1,3 @@
+// The TeleInfo framing and parsing are exposed as a library so that benches and
+// integration tests can use them, the rest of the daemon lives in the binary
+pub mod teleinfo;
@@ -0,0 +1,150 @@
+use std::fs;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, UnixListener};
+use tokio::sync::broadcast;
+use tracing::{event, instrument, Level};
+
+// Number of frames a client may lag behind before being dropped, so that a slow
+// client never back-pressures the meter reader
+const CLIENT_BUFFER_FRAMES: usize = 16;
+
+// Sends the frames to the connected clients, and removes the Unix socket once
+// dropped
+pub struct LocalStream {
+    sender: broadcast::Sender<String>,
+    socket_path: Option<PathBuf>,
+}
+
+impl LocalStream {
+    // Only fails when no client is connected
+    pub fn send(&self, frame: String) -> Result<usize, broadcast::error::SendError<String>> {
+        self.sender.send(frame)
+    }
+}
+
+impl Drop for LocalStream {
+    fn drop(&mut self) {
+        if let Some(path) = &self.socket_path {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+// A socket file left behind by a crash would make the bind fail, anything else at
+// that path is left alone
+fn remove_stale_socket(path: &Path) -> std::io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path),
+        _ => Ok(()),
+    }
+}
+
+// Streams newline-delimited JSON frames to every connected client, on a TCP address
+// such as "127.0.0.1:9090" or a Unix socket such as "unix:/run/teleinfo.sock"
+#[instrument]
+pub async fn serve(addr: String) -> std::io::Result<LocalStream> {
+    let (sender, _) = broadcast::channel(CLIENT_BUFFER_FRAMES);
+
+    let socket_path = match addr.strip_prefix("unix:") {
+        Some(path) => {
+            remove_stale_socket(Path::new(path))?;
+            let listener = UnixListener::bind(path)?;
+            event!(Level::INFO, path, "Streaming frames on Unix socket");
+
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                loop {
+                    match listener.accept().await {
+                        Ok((socket, _)) => {
+                            event!(Level::DEBUG, "Local stream client connected");
+                            tokio::spawn(serve_client(socket, sender.subscribe()));
+                        }
+                        Err(e) => {
+                            event!(Level::ERROR, error = ?e, "Error accepting local stream client");
+                        }
+                    }
+                }
+            });
+            Some(PathBuf::from(path))
+        }
+        None => {
+            let listener = TcpListener::bind(&addr).await?;
+            event!(Level::INFO, addr, "Streaming frames on TCP");
+
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                loop {
+                    match listener.accept().await {
+                        Ok((socket, peer)) => {
+                            event!(Level::DEBUG, ?peer, "Local stream client connected");
+                            tokio::spawn(serve_client(socket, sender.subscribe()));
+                        }
+                        Err(e) => {
+                            event!(Level::ERROR, error = ?e, "Error accepting local stream client");
+                        }
+                    }
+                }
+            });
+            None
+        }
+    };
+
+    Ok(LocalStream {
+        sender,
+        socket_path,
+    })
+}
+
+async fn serve_client<W: AsyncWrite + Unpin>(
+    mut writer: W,
+    mut receiver: broadcast::Receiver<String>,
+) {
+    loop {
+        match receiver.recv().await {
+            Ok(frame) => {
+                if let Err(e) = writer.write_all(format!("{}\n", frame).as_bytes()).await {
+                    event!(Level::DEBUG, error = ?e, "Local stream client disconnected");
+                    return;
+                }
+            }
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                event!(
+                    Level::WARN,
+                    skipped,
+                    "Dropping local stream client that fell behind"
+                );
+                return;
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::os::unix::net;
+    use std::process;
+
+    #[tokio::test]
+    async fn test_serve_unix_socket_twice() {
+        let path = env::temp_dir().join(format!("teleinfo-local-stream-{}.sock", process::id()));
+        let addr = format!("unix:{}", path.display());
+
+        // A socket left behind by a crash
+        drop(net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let local_stream = serve(addr.clone()).await.unwrap();
+        drop(local_stream);
+        assert!(!path.exists());
+
+        let local_stream = serve(addr.clone()).await.unwrap();
+        assert!(path.exists());
+        drop(local_stream);
+        assert!(!path.exists());
+    }
+}
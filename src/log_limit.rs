@@ -0,0 +1,62 @@
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(60);
+
+// Lets a repeated error be logged at most once per interval, e.g. on every frame
+// during a broker outage, and counts the occurrences that weren't logged
+pub struct LogLimiter {
+    interval: Duration,
+    last_logged: Option<Instant>,
+    suppressed: u64,
+}
+
+impl LogLimiter {
+    pub fn new(interval: Duration) -> Self {
+        LogLimiter {
+            interval,
+            last_logged: None,
+            suppressed: 0,
+        }
+    }
+
+    // Returns the number of occurrences suppressed since the last log when this one
+    // should be logged
+    pub fn check(&mut self) -> Option<u64> {
+        if self
+            .last_logged
+            .is_some_and(|last_logged| last_logged.elapsed() < self.interval)
+        {
+            self.suppressed += 1;
+            return None;
+        }
+
+        self.last_logged = Some(Instant::now());
+        Some(std::mem::take(&mut self.suppressed))
+    }
+
+    // Once the error is gone, its next occurrence is logged right away
+    pub fn reset(&mut self) {
+        self.last_logged = None;
+        self.suppressed = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_limiter() {
+        let mut log_limiter = LogLimiter::new(Duration::from_secs(60));
+        assert_eq!(log_limiter.check(), Some(0));
+        assert_eq!(log_limiter.check(), None);
+        assert_eq!(log_limiter.check(), None);
+
+        log_limiter.reset();
+        assert_eq!(log_limiter.check(), Some(0));
+
+        let mut log_limiter = LogLimiter::new(Duration::ZERO);
+        assert_eq!(log_limiter.check(), Some(0));
+        assert_eq!(log_limiter.check(), Some(0));
+    }
+}
@@ -1,19 +1,77 @@
+use clap::Parser;
+use futures_util::future;
 use futures_util::pin_mut;
 use futures_util::stream::StreamExt;
-use rppal::gpio::Gpio;
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::runtime;
+use tokio::signal;
+use tokio::sync::broadcast;
+use tokio::task::JoinSet;
+use tokio::time;
 use tracing::{event, Level};
+use tracing_subscriber::EnvFilter;
 
+mod emoncms;
+mod frame_log;
+mod index;
+mod led;
+mod local_stream;
+mod log_limit;
 mod mqtt;
+mod power_alert;
 mod serial;
-mod teleinfo;
+mod statsd;
+mod status;
 
-const GPIO_PITINFO_GREEN_LED: u8 = 4;
+use teleinfo2mqtt_rs::teleinfo;
+
+const DEFAULT_FRAME_BUFFER_CAPACITY: usize = 16;
+
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Cli {
+    /// Parse a raw TeleInfo capture file, print the parsed frames or errors, and exit
+    #[arg(long, value_name = "FILE")]
+    check: Option<PathBuf>,
+
+    /// Exit after the first frame has been successfully published
+    #[arg(long)]
+    once: bool,
+}
+
+fn main() {
+    // The workload is light, a single thread is plenty on small boards like a Pi Zero
+    let single_thread = match env::var("TOKIO_SINGLE_THREAD") {
+        Ok(single_thread) => single_thread
+            .parse::<bool>()
+            .expect("$TOKIO_SINGLE_THREAD is not a valid boolean"),
+        Err(_) => false,
+    };
+    let mut runtime = if single_thread {
+        runtime::Builder::new_current_thread()
+    } else {
+        runtime::Builder::new_multi_thread()
+    };
+    runtime
+        .enable_all()
+        .build()
+        .expect("Failed to build the Tokio runtime")
+        .block_on(run());
+}
+
+async fn run() {
+    let cli = Cli::parse();
 
-#[tokio::main]
-async fn main() {
     let log_level: tracing::Level = match env::var("LOG_LEVEL") {
         Ok(level) => match level.to_lowercase().as_str() {
             "trace" => tracing::Level::TRACE,
@@ -31,10 +89,42 @@ async fn main() {
         .with_file(true)
         .with_line_number(true)
         .with_thread_ids(true)
-        .with_target(true)
-        .with_max_level(log_level)
-        .finish();
-    tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
+        .with_target(true);
+    // RUST_LOG allows per-module levels, e.g. "info,teleinfo2mqtt_rs::serial=debug"
+    match env::var("RUST_LOG") {
+        Ok(_) => tracing::subscriber::set_global_default(
+            subscriber
+                .with_env_filter(EnvFilter::from_default_env())
+                .finish(),
+        ),
+        Err(_) => {
+            tracing::subscriber::set_global_default(subscriber.with_max_level(log_level).finish())
+        }
+    }
+    .expect("setting default subscriber failed");
+
+    let parse_strict = match env::var("PARSE_STRICT") {
+        Ok(strict) => strict
+            .parse::<bool>()
+            .expect("$PARSE_STRICT is not a valid boolean"),
+        Err(_) => true,
+    };
+
+    let framing = teleinfo::stream::Framing {
+        start: match env::var("FRAME_START_BYTE") {
+            Ok(byte) => parse_byte(&byte).expect("$FRAME_START_BYTE is not a valid byte"),
+            Err(_) => teleinfo::stream::Framing::default().start,
+        },
+        end: match env::var("FRAME_END_BYTE") {
+            Ok(byte) => parse_byte(&byte).expect("$FRAME_END_BYTE is not a valid byte"),
+            Err(_) => teleinfo::stream::Framing::default().end,
+        },
+    };
+
+    if let Some(path) = cli.check {
+        let all_valid = check_capture(path, framing, parse_strict).await;
+        process::exit(if all_valid { 0 } else { 1 });
+    }
 
     let mqtt_host = env::var("MQTT_HOST").expect("$MQTT_HOST is not set");
     let mqtt_port = match env::var("MQTT_PORT") {
@@ -43,48 +133,709 @@ async fn main() {
             .expect("$MQTT_PORT is not a valid port number"),
         Err(_) => 1883,
     };
+    // aimeqtt only speaks MQTT over TCP, fail early rather than connecting to a
+    // WebSocket listener that will never answer MQTT
+    match env::var("MQTT_TRANSPORT").as_deref() {
+        Ok("tcp") | Err(_) => {}
+        Ok("websocket") => panic!(
+            "$MQTT_TRANSPORT=websocket is not supported, the MQTT client only connects over TCP"
+        ),
+        Ok(transport) => panic!("$MQTT_TRANSPORT {:?} must be tcp", transport),
+    }
+    let mqtt_base_topic =
+        env::var("MQTT_BASE_TOPIC").unwrap_or(mqtt::DEFAULT_BASE_TOPIC.to_string());
+    let state_topic_template =
+        env::var("STATE_TOPIC_TEMPLATE").unwrap_or(mqtt::DEFAULT_STATE_TOPIC_TEMPLATE.to_string());
+    let hostname = if state_topic_template.contains("{hostname}") {
+        fs::read_to_string("/proc/sys/kernel/hostname")
+            .expect("Failed to read the hostname")
+            .trim()
+            .to_string()
+    } else {
+        String::new()
+    };
+    let topic_use_adco = match env::var("TOPIC_USE_ADCO") {
+        Ok(use_adco) => use_adco
+            .parse::<bool>()
+            .expect("$TOPIC_USE_ADCO is not a valid boolean"),
+        Err(_) => true,
+    };
+    let topic_id = match (topic_use_adco, env::var("TOPIC_ALIAS")) {
+        (true, _) => mqtt::TopicId::Adco,
+        (false, Ok(aliases)) => mqtt::TopicId::Aliases(
+            mqtt::parse_topic_aliases(&aliases).expect("$TOPIC_ALIAS is not a valid alias list"),
+        ),
+        (false, Err(_)) => mqtt::TopicId::AdcoHash,
+    };
+    let state_topic = mqtt::StateTopic::new(&state_topic_template, &mqtt_base_topic, &hostname)
+        .expect("$STATE_TOPIC_TEMPLATE is not a valid topic template")
+        .with_topic_id(topic_id);
+    let mqtt_connect_max_attempts = env::var("MQTT_CONNECT_MAX_ATTEMPTS").ok().map(|attempts| {
+        attempts
+            .parse::<u32>()
+            .expect("$MQTT_CONNECT_MAX_ATTEMPTS is not a valid number")
+    });
+    let log_repeat_interval = match env::var("LOG_REPEAT_INTERVAL") {
+        Ok(interval) => Duration::from_secs(
+            interval
+                .parse::<u64>()
+                .expect("$LOG_REPEAT_INTERVAL is not a valid number of seconds"),
+        ),
+        Err(_) => log_limit::DEFAULT_INTERVAL,
+    };
+    let publish_retries = match env::var("PUBLISH_RETRIES") {
+        Ok(retries) => retries
+            .parse::<u32>()
+            .expect("$PUBLISH_RETRIES is not a valid number"),
+        Err(_) => mqtt::DEFAULT_PUBLISH_RETRIES,
+    };
     let mqtt_user = env::var("MQTT_USER");
-    let mqtt_pass = env::var("MQTT_PASS");
+    // A password file takes precedence, so that it can be mounted as a secret
+    let mqtt_pass_file = env::var("MQTT_PASS_FILE").ok();
+    let mqtt_pass = match &mqtt_pass_file {
+        Some(path) => Ok(mqtt::read_password_file(path).expect("Failed to read $MQTT_PASS_FILE")),
+        None => env::var("MQTT_PASS"),
+    };
     let serial_device = match env::var("SERIAL_PORT") {
         Ok(port) => port,
         Err(_) => "/dev/ttyS0".to_string(),
     };
+    let serial_wait_timeout = match env::var("SERIAL_WAIT_TIMEOUT") {
+        Ok(timeout) => {
+            Some(Duration::from_secs(timeout.parse::<u64>().expect(
+                "$SERIAL_WAIT_TIMEOUT is not a valid number of seconds",
+            )))
+        }
+        Err(_) => None,
+    };
+    let serial_auto_baud_timeout = match env::var("SERIAL_AUTO_BAUD") {
+        Ok(timeout) => {
+            Some(Duration::from_secs(timeout.parse::<u64>().expect(
+                "$SERIAL_AUTO_BAUD is not a valid number of seconds",
+            )))
+        }
+        Err(_) => None,
+    };
+    let serial_read_timeout = env::var("SERIAL_READ_TIMEOUT_MS").ok().map(|timeout| {
+        timeout
+            .parse::<u64>()
+            .ok()
+            .map(Duration::from_millis)
+            .filter(|timeout| {
+                *timeout >= Duration::from_millis(100) && *timeout <= serial::MAX_READ_TIMEOUT
+            })
+            .expect("$SERIAL_READ_TIMEOUT_MS must be between 100 and 25500 milliseconds")
+    });
+    let replay_at_eof = match env::var("REPLAY_AT_EOF") {
+        Ok(behavior) => behavior
+            .parse::<serial::EofBehavior>()
+            .expect("$REPLAY_AT_EOF must be loop, exit or idle"),
+        Err(_) => serial::EofBehavior::Exit,
+    };
+    let serial_mask_parity = match env::var("SERIAL_MASK_PARITY") {
+        Ok(mask_parity) => mask_parity
+            .parse::<bool>()
+            .expect("$SERIAL_MASK_PARITY is not a valid boolean"),
+        Err(_) => false,
+    };
+    let domoticz_idx = mqtt::DomoticzIdx {
+        p1: env::var("DOMOTICZ_IDX_P1").ok().map(|idx| {
+            idx.parse::<u32>()
+                .expect("$DOMOTICZ_IDX_P1 is not a valid Domoticz idx")
+        }),
+        papp: env::var("DOMOTICZ_IDX_PAPP").ok().map(|idx| {
+            idx.parse::<u32>()
+                .expect("$DOMOTICZ_IDX_PAPP is not a valid Domoticz idx")
+        }),
+    };
+    let emoncms = match (env::var("EMONCMS_URL"), env::var("EMONCMS_APIKEY")) {
+        (Ok(url), Ok(api_key)) => {
+            Some(emoncms::Emoncms::new(&url, api_key).expect("$EMONCMS_URL is not a valid URL"))
+        }
+        (Ok(_), Err(_)) => panic!("$EMONCMS_APIKEY is required with $EMONCMS_URL"),
+        (Err(_), _) => None,
+    };
+    let allowed_adcos = env::var("ALLOWED_ADCO").ok().map(|adcos| {
+        adcos
+            .split(',')
+            .map(|adco| adco.trim().to_string())
+            .filter(|adco| !adco.is_empty())
+            .collect::<Vec<_>>()
+    });
+    let frame_log_max_bytes = match env::var("FRAME_LOG_MAX_BYTES") {
+        Ok(max_bytes) => max_bytes
+            .parse::<u64>()
+            .expect("$FRAME_LOG_MAX_BYTES is not a valid number of bytes"),
+        Err(_) => frame_log::DEFAULT_MAX_BYTES,
+    };
+    let frame_log_keep = match env::var("FRAME_LOG_KEEP") {
+        Ok(keep) => keep
+            .parse::<usize>()
+            .expect("$FRAME_LOG_KEEP is not a valid number of files"),
+        Err(_) => frame_log::DEFAULT_KEEP,
+    };
+    let frame_log_file = env::var("FRAME_LOG_FILE").ok();
+    let mut frame_log = frame_log_file.as_ref().map(|path| {
+        frame_log::FrameLog::open(PathBuf::from(path), frame_log_max_bytes, frame_log_keep)
+            .expect("Failed to open $FRAME_LOG_FILE")
+    });
+    let power_alert_debounce = match env::var("POWER_ALERT_DEBOUNCE_S") {
+        Ok(debounce) => Duration::from_secs(
+            debounce
+                .parse::<u64>()
+                .expect("$POWER_ALERT_DEBOUNCE_S is not a valid number of seconds"),
+        ),
+        Err(_) => power_alert::DEFAULT_DEBOUNCE,
+    };
+    let power_alert_va = env::var("POWER_ALERT_VA").ok().map(|threshold| {
+        threshold
+            .parse::<u32>()
+            .expect("$POWER_ALERT_VA is not a valid power in VA")
+    });
+    let mut power_alert = power_alert_va
+        .map(|threshold| power_alert::PowerAlert::new(threshold, power_alert_debounce));
+    let index_spike_policy = match env::var("INDEX_SPIKE_POLICY") {
+        Ok(policy) => policy
+            .parse::<index::IndexPolicy>()
+            .expect("$INDEX_SPIKE_POLICY must be warn or drop"),
+        Err(_) => index::IndexPolicy::Warn,
+    };
+    let dry_run = match env::var("DRY_RUN") {
+        Ok(dry_run) => dry_run
+            .parse::<bool>()
+            .expect("$DRY_RUN is not a valid boolean"),
+        Err(_) => false,
+    };
+    let publish_on_change = env::var("PUBLISH_ON_CHANGE").ok().map(|labels| {
+        let labels = labels
+            .split(',')
+            .map(|label| label.trim().to_uppercase())
+            .filter(|label| !label.is_empty())
+            .collect::<Vec<_>>();
+        for label in &labels {
+            if !teleinfo::parser::LABELS.contains(&label.as_str()) {
+                panic!("$PUBLISH_ON_CHANGE contains the unknown label {:?}", label);
+            }
+        }
+        labels
+    });
+    let include_seq = match env::var("INCLUDE_SEQ") {
+        Ok(include_seq) => include_seq
+            .parse::<bool>()
+            .expect("$INCLUDE_SEQ is not a valid boolean"),
+        Err(_) => false,
+    };
+    let pretty_json = match env::var("PRETTY_JSON") {
+        Ok(pretty_json) => pretty_json
+            .parse::<bool>()
+            .expect("$PRETTY_JSON is not a valid boolean"),
+        Err(_) => false,
+    };
+    let frame_buffer_capacity = match env::var("FRAME_BUFFER_CAPACITY") {
+        Ok(capacity) => capacity
+            .parse::<usize>()
+            .ok()
+            .filter(|capacity| *capacity > 0)
+            .expect("$FRAME_BUFFER_CAPACITY is not a valid number of frames"),
+        Err(_) => DEFAULT_FRAME_BUFFER_CAPACITY,
+    };
+    let exit_on_stall = match env::var("EXIT_ON_STALL_SECONDS") {
+        Ok(timeout) => {
+            Some(Duration::from_secs(timeout.parse::<u64>().expect(
+                "$EXIT_ON_STALL_SECONDS is not a valid number of seconds",
+            )))
+        }
+        Err(_) => None,
+    };
+    let last_frame_age_interval = match env::var("LAST_FRAME_AGE_INTERVAL") {
+        Ok(interval) => Some(Duration::from_secs(
+            interval
+                .parse::<u64>()
+                .ok()
+                .filter(|interval| *interval > 0)
+                .expect("$LAST_FRAME_AGE_INTERVAL is not a valid number of seconds"),
+        )),
+        Err(_) => None,
+    };
+    let gpio_chip = env::var("GPIO_CHIP").ok();
+    let local_stream_addr = env::var("LOCAL_STREAM_ADDR").ok();
+    let status_addr = env::var("STATUS_ADDR").ok();
+    let statsd_addr = env::var("STATSD_ADDR").ok();
+
+    // Non-secret configuration, published once the ADCO is known to help debugging
+    // remote deployments. Credentials are only reported as present or not.
+    let mut effective_config = serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "serial_port": serial_device,
+        // Filled in once a frame came through, SERIAL_AUTO_BAUD may switch it
+        "baud_rate": null,
+        "serial_auto_baud_s": serial_auto_baud_timeout.map(|timeout| timeout.as_secs()),
+        "serial_read_timeout_ms": serial_read_timeout.map(|timeout| timeout.as_millis() as u64),
+        "serial_mask_parity": serial_mask_parity,
+        "replay_at_eof": format!("{:?}", replay_at_eof).to_lowercase(),
+        "frame_start_byte": framing.start,
+        "frame_end_byte": framing.end,
+        "parse_strict": parse_strict,
+        "dry_run": dry_run,
+        "mqtt_host": mqtt_host,
+        "mqtt_port": mqtt_port,
+        "mqtt_credentials": mqtt_user.is_ok() && mqtt_pass.is_ok(),
+        "mqtt_connect_max_attempts": mqtt_connect_max_attempts,
+        "publish_retries": publish_retries,
+        "base_topic": mqtt_base_topic,
+        "state_topic_template": state_topic_template,
+        "topic_use_adco": topic_use_adco,
+        "publish_on_change": publish_on_change,
+        "include_seq": include_seq,
+        "pretty_json": pretty_json,
+        "allowed_adco": allowed_adcos,
+        "index_spike_policy": format!("{:?}", index_spike_policy).to_lowercase(),
+        "power_alert_va": power_alert_va,
+        "power_alert_debounce_s": power_alert_debounce.as_secs(),
+        "domoticz_idx_p1": domoticz_idx.p1,
+        "domoticz_idx_papp": domoticz_idx.papp,
+        "emoncms": emoncms.is_some(),
+        "frame_log_file": frame_log_file,
+        "frame_buffer_capacity": frame_buffer_capacity,
+        "exit_on_stall_s": exit_on_stall.map(|timeout| timeout.as_secs()),
+        "last_frame_age_interval_s": last_frame_age_interval.map(|interval| interval.as_secs()),
+        "log_repeat_interval_s": log_repeat_interval.as_secs(),
+        "gpio_chip": gpio_chip,
+        "local_stream_addr": local_stream_addr,
+        "status_addr": status_addr,
+        "statsd_addr": statsd_addr,
+    });
+
+    // Brokers are connected to concurrently, and we go on as soon as one of them is,
+    // so that an unreachable broker doesn't hold up the others
+    let credentials = match (&mqtt_user, &mqtt_pass) {
+        (Ok(mqtt_user), Ok(mqtt_pass)) => Some((mqtt_user.clone(), mqtt_pass.clone())),
+        _ => None,
+    };
+    let brokers = mqtt::Brokers::new(credentials).with_dry_run(dry_run);
+    let mut connecting = JoinSet::new();
+    for broker in mqtt_host
+        .split(',')
+        .map(str::trim)
+        .filter(|broker| !broker.is_empty())
+    {
+        let (host, port) =
+            mqtt::parse_broker(broker, mqtt_port).expect("$MQTT_HOST is not a valid broker list");
+
+        let brokers = brokers.clone();
+        let broker = broker.to_string();
+        connecting.spawn(async move {
+            brokers
+                .connect(broker, host, port, mqtt_connect_max_attempts)
+                .await
+        });
+    }
+    loop {
+        match connecting.join_next().await {
+            Some(Ok(Ok(()))) => break,
+            Some(_) => continue,
+            None => panic!("MQTT broker is not reachable"),
+        }
+    }
+    tokio::spawn(async move { while connecting.join_next().await.is_some() {} });
+    event!(Level::DEBUG, "MQTT clients created");
 
-    let mut aimeqtt_options =
-        aimeqtt::client::ClientOptions::new(mqtt_host, mqtt_port).with_keep_alive(60);
-    if mqtt_user.is_ok() && mqtt_pass.is_ok() {
-        aimeqtt_options = aimeqtt_options.with_credentials(mqtt_user.unwrap(), mqtt_pass.unwrap());
+    if let (Some(path), Ok(_), Ok(mqtt_pass)) = (mqtt_pass_file, &mqtt_user, mqtt_pass) {
+        tokio::spawn(mqtt::watch_password_file(brokers.clone(), path, mqtt_pass));
     }
 
-    let client = aimeqtt::client::new(aimeqtt_options).await;
-    event!(Level::DEBUG, "MQTT client created");
+    let local_stream = match local_stream_addr {
+        Some(addr) => Some(
+            local_stream::serve(addr)
+                .await
+                .expect("Failed to start local stream server"),
+        ),
+        None => None,
+    };
 
-    let serial_stream = serial::serial_stream(serial_device);
-    pin_mut!(serial_stream);
+    let status = Arc::new(status::Status::new());
+    if let Some(addr) = status_addr {
+        status::serve(addr, status.clone())
+            .await
+            .expect("Failed to start status server");
+    }
+    if let Some(addr) = statsd_addr {
+        statsd::serve(addr, status.clone())
+            .await
+            .expect("Failed to set up StatsD metrics");
+    }
 
-    let teleinfo_raw_frames_stream = teleinfo::stream::ascii_to_frames(serial_stream);
-    pin_mut!(teleinfo_raw_frames_stream);
+    let emoncms = emoncms.map(|emoncms| emoncms.spawn(publish_retries, log_repeat_interval));
 
-    let teleinfo_parsed_frames_stream =
-        teleinfo::stream::frame_to_teleinfo(teleinfo_raw_frames_stream);
-    pin_mut!(teleinfo_parsed_frames_stream);
+    // The LED is only a visual aid, not being able to drive it isn't fatal
+    let mut led = match led::Led::new(gpio_chip.as_deref()) {
+        Ok(led) => Some(led),
+        Err(e) => {
+            event!(Level::WARN, error = %e, "Failed to set up the LED, running without it");
+            None
+        }
+    };
+
+    // Only known when reading from a UART
+    let serial_baud_rate = Arc::new(AtomicU32::new(0));
+    let (mut frame_receiver, reader) = spawn_reader(
+        serial::SerialOptions {
+            device: serial_device,
+            wait_timeout: serial_wait_timeout,
+            auto_baud_timeout: serial_auto_baud_timeout,
+            read_timeout: serial_read_timeout,
+            mask_parity: serial_mask_parity,
+            log_interval: log_repeat_interval,
+            at_eof: replay_at_eof,
+            baud_rate: serial_baud_rate.clone(),
+            frame_received: Arc::new(AtomicBool::new(false)),
+        },
+        framing,
+        parse_strict,
+        frame_buffer_capacity,
+        status.clone(),
+    );
+
+    let mut index_monitor = index::IndexMonitor::new(index_spike_policy);
+    let mut config_published = false;
+    let mut unknown_adco_log = log_limit::LogLimiter::new(log_repeat_interval);
+    let mut frame_log_error_log = log_limit::LogLimiter::new(log_repeat_interval);
+    let mut publish_error_log = log_limit::LogLimiter::new(log_repeat_interval);
+    let mut domoticz_error_log = log_limit::LogLimiter::new(log_repeat_interval);
+    let mut last_frame_at = time::Instant::now();
+    let mut last_adco: Option<String> = None;
+    let mut frame_seqs: HashMap<String, u64> = HashMap::new();
+    let mut last_published: HashMap<String, teleinfo::parser::TeleinfoFrame> = HashMap::new();
+    let mut last_frame_age_ticks =
+        time::interval(last_frame_age_interval.unwrap_or(Duration::from_secs(1)));
+    last_frame_age_ticks.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+    let shutdown = shutdown_signal();
+    pin_mut!(shutdown);
+    loop {
+        let stall_deadline = exit_on_stall.map(|timeout| last_frame_at + timeout);
+        let received = tokio::select! {
+            received = frame_receiver.recv() => received,
+            _ = time::sleep_until(stall_deadline.unwrap_or(last_frame_at)),
+                if stall_deadline.is_some() =>
+            {
+                // Let the service manager restart us rather than hanging forever
+                event!(
+                    Level::ERROR,
+                    timeout = ?exit_on_stall,
+                    "No frame received in time, exiting"
+                );
+                drop(led);
+                process::exit(1);
+            }
+            _ = &mut shutdown => {
+                event!(Level::INFO, "Received shutdown signal, exiting");
+                break;
+            }
+            // Published between frames too, so that a quiet meter shows up
+            _ = last_frame_age_ticks.tick(),
+                if last_frame_age_interval.is_some() && last_adco.is_some() =>
+            {
+                let adco = last_adco.as_deref().unwrap_or_default();
+                let age = last_frame_at.elapsed();
+                if let Err(e) = mqtt::publish_last_frame_age(&brokers, &state_topic, adco, age).await {
+                    event!(Level::DEBUG, error = ?e, "Error while publishing last frame age to MQTT");
+                }
+                continue;
+            }
+        };
+        let mut value = match received {
+            Ok(value) => value,
+            Err(broadcast::error::RecvError::Lagged(dropped)) => {
+                status.record_dropped_frames(dropped);
+                event!(
+                    Level::WARN,
+                    dropped,
+                    "Publishing is too slow, dropped the oldest frames"
+                );
+                continue;
+            }
+            // The reader is done, which is only expected at the end of the input
+            Err(broadcast::error::RecvError::Closed) => {
+                match reader.join() {
+                    Ok(Ok(())) => break,
+                    Ok(Err(e)) => {
+                        event!(Level::ERROR, error = ?e, "Failed to read from the serial source, exiting");
+                    }
+                    // The panic message has already been printed
+                    Err(_) => event!(Level::ERROR, "Serial reader failed, exiting"),
+                }
+                // Let the service manager restart us
+                drop(led);
+                process::exit(1);
+            }
+        };
+        // Guards against publishing another meter's frames on a shared line
+        if allowed_adcos
+            .as_ref()
+            .is_some_and(|adcos| !adcos.contains(&value.adco))
+        {
+            if let Some(suppressed) = unknown_adco_log.check() {
+                event!(
+                    Level::WARN,
+                    adco = value.adco,
+                    suppressed,
+                    "Dropping frame from a meter not in $ALLOWED_ADCO"
+                );
+            }
+            continue;
+        }
+
+        status.record_frame(&value);
+        last_frame_at = time::Instant::now();
+        last_adco = Some(value.adco.clone());
+
+        // Counted before anything may skip the frame, so that consumers see a gap
+        // for every frame they missed
+        if include_seq {
+            let seq = frame_seqs.entry(value.adco.clone()).or_insert(0);
+            *seq += 1;
+            value.seq = Some(*seq);
+        }
+
+        // Only the index is dropped, the rest of the frame is still good
+        if !index_monitor.check(&value, Instant::now()) {
+            value.base = None;
+        }
+
+        if !config_published {
+            let baud_rate = serial_baud_rate.load(Ordering::Relaxed);
+            if baud_rate != 0 {
+                effective_config["baud_rate"] = baud_rate.into();
+            }
+            match mqtt::publish_config(
+                &brokers,
+                &state_topic,
+                &value.adco,
+                &effective_config.to_string(),
+            )
+            .await
+            {
+                Ok(_) => config_published = true,
+                Err(e) => {
+                    event!(Level::ERROR, error = ?e, "Error while publishing configuration to MQTT");
+                }
+            }
+        }
+
+        if let Some(local_stream) = &local_stream {
+            // Sending only fails when no client is connected, which is fine
+            let _ = local_stream.send(value.to_string());
+        }
+
+        if let Some(frame_log) = &mut frame_log {
+            match frame_log.write(value.to_json(), SystemTime::now()) {
+                Ok(_) => frame_log_error_log.reset(),
+                Err(e) => {
+                    if let Some(suppressed) = frame_log_error_log.check() {
+                        event!(Level::ERROR, error = ?e, suppressed, "Error while writing frame to $FRAME_LOG_FILE");
+                    }
+                }
+            }
+        }
+
+        if let Some(power_alert) = &mut power_alert {
+            if let Some(active) = power_alert.check(&value, Instant::now()) {
+                if let Err(e) =
+                    mqtt::publish_power_alert(&brokers, &state_topic, &value.adco, active).await
+                {
+                    event!(Level::ERROR, error = ?e, "Error while publishing power alert to MQTT");
+                }
+            }
+        }
+
+        // Only the chosen fields are compared, as the energy index changes with almost
+        // every frame
+        let changed = match &publish_on_change {
+            Some(labels) => last_published
+                .get(&value.adco)
+                .is_none_or(|last| value.differs_in(last, labels)),
+            None => true,
+        };
+        let published = if changed {
+            let publish_start = Instant::now();
+            let publish_result = mqtt::publish_teleinfo(
+                &brokers,
+                &state_topic,
+                &value,
+                publish_retries,
+                pretty_json,
+            )
+            .await;
+            let publish_duration = publish_start.elapsed();
+            status.record_publish_duration(publish_duration);
+            event!(Level::DEBUG, ?publish_duration, "Published teleinfo frame");
 
-    while let Some(value) = teleinfo_parsed_frames_stream.next().await {
-        match mqtt::publish_teleinfo(&client, &value).await {
-            Ok(_) => {
-                let mut pin = Gpio::new()
-                    .unwrap()
-                    .get(GPIO_PITINFO_GREEN_LED)
-                    .unwrap()
-                    .into_output();
+            let published = match publish_result {
+                Ok(_) => {
+                    publish_error_log.reset();
+                    if let Some(led) = &mut led {
+                        led.pulse();
+                    }
+                    if publish_on_change.is_some() {
+                        last_published.insert(value.adco.clone(), value.clone());
+                    }
+                    true
+                }
+                Err(e) => {
+                    status.record_failed_publish();
+                    if let Some(suppressed) = publish_error_log.check() {
+                        event!(Level::ERROR, error = ?e, suppressed, "Error while publishing teleinfo frame to MQTT, dropping it");
+                    }
+                    false
+                }
+            };
+            status.set_mqtt_connected(published);
+            published
+        } else {
+            event!(Level::DEBUG, "Teleinfo frame unchanged, not publishing it");
+            false
+        };
 
-                pin.set_high();
-                thread::sleep(Duration::from_millis(10));
-                pin.set_low();
+        if domoticz_idx.is_enabled() {
+            match mqtt::publish_domoticz(&brokers, &domoticz_idx, &value).await {
+                Ok(_) => domoticz_error_log.reset(),
+                Err(e) => {
+                    if let Some(suppressed) = domoticz_error_log.check() {
+                        event!(Level::ERROR, error = ?e, suppressed, "Error while publishing teleinfo frame to Domoticz");
+                    }
+                }
+            }
+        }
+
+        if let Some(emoncms) = &emoncms {
+            // Fails only once the Emoncms task is gone
+            let _ = emoncms.send(value.clone());
+        }
+
+        if cli.once && published {
+            event!(Level::INFO, "Published one frame, exiting");
+            break;
+        }
+    }
+}
+
+// Resolves on SIGINT or SIGTERM (sent by systemd and docker stop), so that the main
+// loop can return, the LED be turned off and the local stream socket removed
+async fn shutdown_signal() {
+    let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())
+        .expect("Failed to listen for SIGTERM");
+    tokio::select! {
+        _ = signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+// Reads and parses frames in their own thread, so that a slow MQTT broker never
+// stalls the serial port, and the blocking serial reads never stall the runtime
+// (which may have a single thread). Once the buffer is full the oldest frames are dropped,
+// and the receiver is told how many it missed. The thread ends with the read error
+// that stopped it, if any.
+fn spawn_reader(
+    serial_options: serial::SerialOptions,
+    framing: teleinfo::stream::Framing,
+    parse_strict: bool,
+    frame_buffer_capacity: usize,
+    status: Arc<status::Status>,
+) -> (
+    broadcast::Receiver<teleinfo::parser::TeleinfoFrame>,
+    thread::JoinHandle<io::Result<()>>,
+) {
+    let (frame_sender, frame_receiver) = broadcast::channel(frame_buffer_capacity);
+
+    let runtime = runtime::Handle::current();
+    let reader = thread::spawn(move || {
+        runtime.block_on(async move {
+            let read_error = RefCell::new(None);
+            let frame_received = serial_options.frame_received.clone();
+            // The read error, always the last item, is kept to be returned
+            let serial_stream = serial::serial_stream(serial_options).filter_map(|bytes| {
+                future::ready(match bytes {
+                    Ok(bytes) => Some(bytes),
+                    Err(e) => {
+                        *read_error.borrow_mut() = Some(e);
+                        None
+                    }
+                })
+            });
+            pin_mut!(serial_stream);
+
+            let teleinfo_raw_frames_stream =
+                teleinfo::stream::ascii_to_frames(serial_stream, framing, status.noise_bytes());
+            pin_mut!(teleinfo_raw_frames_stream);
+
+            let teleinfo_parsed_frames_stream = teleinfo::stream::frame_to_teleinfo(
+                teleinfo_raw_frames_stream,
+                parse_strict,
+                |parse_duration| status.record_parse_duration(parse_duration),
+                status.parse_errors(),
+                frame_received,
+            );
+            pin_mut!(teleinfo_parsed_frames_stream);
+
+            while let Some(value) = teleinfo_parsed_frames_stream.next().await {
+                // Only fails once the main loop is gone
+                if frame_sender.send(value).is_err() {
+                    break;
+                }
+            }
+
+            match read_error.take() {
+                Some(e) => Err(e),
+                None => Ok(()),
+            }
+        })
+    });
+
+    (frame_receiver, reader)
+}
+
+// Runs a capture through the same framing and parsing as the serial port, without
+// touching MQTT or GPIO, so users can validate their meter's output
+async fn check_capture(
+    path: PathBuf,
+    framing: teleinfo::stream::Framing,
+    parse_strict: bool,
+) -> bool {
+    let file_stream = serial::file_stream(path);
+    pin_mut!(file_stream);
+
+    let noise_bytes = Arc::new(AtomicU64::new(0));
+    let teleinfo_raw_frames_stream =
+        teleinfo::stream::ascii_to_frames(file_stream, framing, noise_bytes.clone());
+    pin_mut!(teleinfo_raw_frames_stream);
+
+    let mut valid_frames = 0;
+    let mut invalid_frames = 0;
+    while let Some(frame) = teleinfo_raw_frames_stream.next().await {
+        match teleinfo::parser::parse_teleinfo(&frame, parse_strict) {
+            Ok(teleinfo) => {
+                valid_frames += 1;
+                println!("{:#}", teleinfo);
             }
             Err(e) => {
-                event!(Level::ERROR, error = ?e, "Error while publishing teleinfo frame to MQTT");
+                invalid_frames += 1;
+                println!("Failed to parse frame {:?}: {}", frame, e);
             }
         }
     }
+
+    let noise_bytes = noise_bytes.load(Ordering::Relaxed);
+    println!(
+        "{} valid frame(s), {} invalid frame(s), {} noise byte(s)",
+        valid_frames, invalid_frames, noise_bytes
+    );
+    invalid_frames == 0 && noise_bytes == 0
+}
+
+// Accepts a byte in hexadecimal (e.g. "0x02") or decimal
+fn parse_byte(byte: &str) -> Option<u8> {
+    match byte.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16).ok(),
+        None => byte.parse::<u8>().ok(),
+    }
 }
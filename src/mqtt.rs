@@ -1,12 +1,629 @@
 use crate::teleinfo::parser::TeleinfoFrame;
-use aimeqtt::client::{Client, ClientError};
+use aimeqtt::client::{self, Client, ClientError, ClientOptions};
+use futures_util::future::join_all;
+use rand::Rng;
+use serde_json::{json, Value};
+use std::cmp;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time;
 use tracing::{event, instrument, Level};
 
-#[instrument(skip(client))]
-pub async fn publish_teleinfo(client: &Client, value: &TeleinfoFrame) -> Result<(), ClientError> {
+const DOMOTICZ_TOPIC: &str = "domoticz/in";
+
+const BROKER_BACKOFF_INITIAL: Duration = Duration::from_secs(1);
+const BROKER_BACKOFF_MAX: Duration = Duration::from_secs(60);
+// Up to 50% is randomly added to each backoff, so that several instances waiting for
+// the same broker don't all retry in lockstep
+const BROKER_BACKOFF_JITTER: f64 = 0.5;
+
+pub const DEFAULT_PUBLISH_RETRIES: u32 = 3;
+const PUBLISH_RETRY_BACKOFF_INITIAL: Duration = Duration::from_millis(200);
+
+// The broker may start after us (e.g. in docker-compose), so wait until it accepts
+// TCP connections before handing over to the MQTT client
+#[instrument]
+pub async fn wait_for_broker(
+    host: &str,
+    port: u16,
+    max_attempts: Option<u32>,
+) -> Result<(), std::io::Error> {
+    let mut backoff = BROKER_BACKOFF_INITIAL;
+    let mut attempt = 1;
+    loop {
+        match TcpStream::connect((host, port)).await {
+            Ok(_) => {
+                event!(Level::DEBUG, attempt, "MQTT broker is reachable");
+                return Ok(());
+            }
+            Err(e) if max_attempts.is_some_and(|max_attempts| attempt >= max_attempts) => {
+                return Err(e);
+            }
+            Err(e) => {
+                let delay = backoff
+                    + backoff.mul_f64(rand::thread_rng().gen_range(0.0..=BROKER_BACKOFF_JITTER));
+                event!(Level::WARN, attempt, error = ?e, ?delay, "MQTT broker is not reachable, retrying");
+                time::sleep(delay).await;
+                backoff = cmp::min(backoff * 2, BROKER_BACKOFF_MAX);
+                attempt += 1;
+            }
+        }
+    }
+}
+
+// Parses a "host" or "host:port" broker address, falling back to the default port.
+// IPv6 addresses are given bare ("::1") or in brackets, with or without a port
+// ("[::1]" or "[::1]:1883").
+pub fn parse_broker(broker: &str, default_port: u16) -> Result<(String, u16), String> {
+    let parse_port = |port: &str| {
+        port.parse::<u16>()
+            .map_err(|_| format!("Invalid port in MQTT broker address {:?}", broker))
+    };
+
+    if let Some(bracketed) = broker.strip_prefix('[') {
+        return match bracketed.split_once(']') {
+            Some((host, "")) => Ok((host.to_string(), default_port)),
+            Some((host, port)) => match port.strip_prefix(':') {
+                Some(port) => Ok((host.to_string(), parse_port(port)?)),
+                None => Err(format!("Invalid MQTT broker address {:?}", broker)),
+            },
+            None => Err(format!("Invalid MQTT broker address {:?}", broker)),
+        };
+    }
+
+    match broker.rsplit_once(':') {
+        // More than one colon is a bare IPv6 address, which can't carry a port
+        Some((host, _)) if host.contains(':') => Ok((broker.to_string(), default_port)),
+        Some((host, port)) => Ok((host.to_string(), parse_port(port)?)),
+        None => Ok((broker.to_string(), default_port)),
+    }
+}
+
+// How often MQTT_PASS_FILE is checked for a new password
+pub const PASSWORD_FILE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+pub fn read_password_file(path: &str) -> std::io::Result<String> {
+    Ok(fs::read_to_string(path)?
+        .trim_end_matches(['\r', '\n'])
+        .to_string())
+}
+
+struct BrokerClient {
+    broker: String,
+    host: String,
+    port: u16,
+    client: Arc<Client>,
+}
+
+// One client per broker, publishes are sent to all of them concurrently so that a
+// failing broker doesn't prevent the others from being updated. Brokers that weren't
+// reachable at startup are added once they come up.
+#[derive(Clone)]
+pub struct Brokers {
+    clients: Arc<RwLock<Vec<BrokerClient>>>,
+    credentials: Arc<RwLock<Option<(String, String)>>>,
+    dry_run: bool,
+}
+
+impl Brokers {
+    pub fn new(credentials: Option<(String, String)>) -> Self {
+        Brokers {
+            clients: Arc::new(RwLock::new(Vec::new())),
+            credentials: Arc::new(RwLock::new(credentials)),
+            dry_run: false,
+        }
+    }
+
+    // In dry run mode, state publishes are only logged, to validate the broker
+    // connection and setup without flooding the real topics
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    fn client_options(&self, host: &str, port: u16) -> ClientOptions {
+        let options = ClientOptions::new(host.to_string(), port).with_keep_alive(60);
+        match self.credentials.read().unwrap().clone() {
+            Some((user, pass)) => options.with_credentials(user, pass),
+            None => options,
+        }
+    }
+
+    // Waits for the broker to be reachable before connecting to it, see wait_for_broker
+    #[instrument(skip(self))]
+    pub async fn connect(
+        &self,
+        broker: String,
+        host: String,
+        port: u16,
+        max_attempts: Option<u32>,
+    ) -> Result<(), std::io::Error> {
+        if let Err(e) = wait_for_broker(&host, port, max_attempts).await {
+            event!(Level::ERROR, error = ?e, "MQTT broker is not reachable, giving up on it");
+            return Err(e);
+        }
+
+        let client = client::new(self.client_options(&host, port)).await;
+        event!(Level::INFO, "Connected to MQTT broker");
+        self.clients.write().unwrap().push(BrokerClient {
+            broker,
+            host,
+            port,
+            client: Arc::new(client),
+        });
+        Ok(())
+    }
+
+    // The clients keep the credentials they were created with, so each broker gets a
+    // new client, replacing the old one once it's connected
+    pub async fn set_password(&self, pass: String) {
+        let Some(user) = self
+            .credentials
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|(user, _)| user.clone())
+        else {
+            return;
+        };
+        *self.credentials.write().unwrap() = Some((user, pass));
+
+        let brokers = self
+            .clients
+            .read()
+            .unwrap()
+            .iter()
+            .map(|client| (client.broker.clone(), client.host.clone(), client.port))
+            .collect::<Vec<_>>();
+        for (broker, host, port) in brokers {
+            let client = Arc::new(client::new(self.client_options(&host, port)).await);
+            event!(
+                Level::INFO,
+                broker,
+                "Reconnected to MQTT broker with the new password"
+            );
+            if let Some(old) = self
+                .clients
+                .write()
+                .unwrap()
+                .iter_mut()
+                .find(|old| old.broker == broker)
+            {
+                old.client = client;
+            }
+        }
+    }
+
+    pub async fn publish_state(&self, topic: String, payload: String) -> Result<(), ClientError> {
+        if self.dry_run {
+            event!(Level::INFO, topic, payload, "Dry run, not publishing state");
+            return Ok(());
+        }
+        self.publish(topic, payload).await
+    }
+
+    // Succeeds as long as at least one broker accepted the message
+    pub async fn publish(&self, topic: String, payload: String) -> Result<(), ClientError> {
+        // Not holding the lock while publishing, brokers may be added meanwhile
+        let clients = self
+            .clients
+            .read()
+            .unwrap()
+            .iter()
+            .map(|client| (client.broker.clone(), client.client.clone()))
+            .collect::<Vec<_>>();
+        let results = join_all(clients.iter().map(|(broker, client)| {
+            let (topic, payload) = (topic.clone(), payload.clone());
+            let several_brokers = clients.len() > 1;
+            async move {
+                let result = client.publish(topic, payload).await;
+                if let Err(e) = &result {
+                    if several_brokers {
+                        event!(Level::WARN, broker, error = ?e, "Error while publishing to MQTT broker");
+                    }
+                }
+                result
+            }
+        }))
+        .await;
+
+        let mut last_error = None;
+        for result in results {
+            match result {
+                Ok(_) => return Ok(()),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.expect("Publishing before any MQTT broker is connected"))
+    }
+}
+
+// Short-lived credentials are rotated by rewriting the password file, which is
+// polled so that the brokers are reconnected to without restarting the daemon
+#[instrument(skip(brokers, pass))]
+pub async fn watch_password_file(brokers: Brokers, path: String, mut pass: String) {
+    let mut ticks = time::interval(PASSWORD_FILE_CHECK_INTERVAL);
+    ticks.tick().await;
+    loop {
+        ticks.tick().await;
+        match read_password_file(&path) {
+            Ok(new_pass) if new_pass != pass => {
+                event!(Level::INFO, "MQTT password changed");
+                brokers.set_password(new_pass.clone()).await;
+                pass = new_pass;
+            }
+            Ok(_) => {}
+            Err(e) => event!(Level::WARN, error = ?e, "Failed to read the MQTT password file"),
+        }
+    }
+}
+
+pub const DEFAULT_BASE_TOPIC: &str = "teleinfo";
+pub const DEFAULT_STATE_TOPIC_TEMPLATE: &str = "{base}/{adco}";
+
+// How the meter is identified in the {adco} part of topics. The ADCO is the meter's
+// serial number, which some users would rather keep out of topics on shared brokers.
+// Meters without an alias fall back to the hash, so that they never share a topic.
+#[derive(Debug)]
+pub enum TopicId {
+    Adco,
+    Aliases(HashMap<String, String>), // Alias by ADCO
+    AdcoHash,
+}
+
+// Parses a comma-separated list of "adco=alias" pairs
+pub fn parse_topic_aliases(aliases: &str) -> Result<HashMap<String, String>, String> {
+    let mut parsed = HashMap::new();
+    for pair in aliases.split(',').filter(|pair| !pair.trim().is_empty()) {
+        let (adco, alias) = pair
+            .split_once('=')
+            .map(|(adco, alias)| (adco.trim(), alias.trim()))
+            .filter(|(adco, alias)| !adco.is_empty() && !alias.is_empty())
+            .ok_or_else(|| format!("Topic alias {:?} is not of the form adco=alias", pair))?;
+        if parsed.values().any(|other| other == alias) {
+            return Err(format!(
+                "Topic alias {:?} is used for several meters",
+                alias
+            ));
+        }
+        parsed.insert(adco.to_string(), alias.to_string());
+    }
+    Ok(parsed)
+}
+
+// Topic the frames are published to, built from a template where {base} and
+// {hostname} are resolved once and {adco} for every frame
+#[derive(Debug)]
+pub struct StateTopic {
+    template: String,
+    topic_id: TopicId,
+}
+
+impl StateTopic {
+    pub fn new(template: &str, base: &str, hostname: &str) -> Result<Self, String> {
+        // Without the ADCO, several meters would publish to the same topic
+        if !template.contains("{adco}") {
+            return Err(format!(
+                "State topic template {:?} must contain {{adco}}",
+                template
+            ));
+        }
+
+        Ok(StateTopic {
+            template: template
+                .replace("{base}", base)
+                .replace("{hostname}", hostname),
+            topic_id: TopicId::Adco,
+        })
+    }
+
+    pub fn with_topic_id(mut self, topic_id: TopicId) -> Self {
+        self.topic_id = topic_id;
+        self
+    }
+
+    pub fn for_adco(&self, adco: &str) -> String {
+        let id = match &self.topic_id {
+            TopicId::Adco => adco.to_string(),
+            TopicId::Aliases(aliases) => match aliases.get(adco) {
+                Some(alias) => alias.clone(),
+                None => format!("{:016x}", fnv1a_hash(adco)),
+            },
+            TopicId::AdcoHash => format!("{:016x}", fnv1a_hash(adco)),
+        };
+        self.template.replace("{adco}", &id)
+    }
+}
+
+// FNV-1a, a hash that, unlike std's DefaultHasher, is guaranteed to be stable across
+// Rust versions, so hashed topics don't change on upgrade
+fn fnv1a_hash(value: &str) -> u64 {
+    value.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[instrument(skip(brokers))]
+pub async fn publish_teleinfo(
+    brokers: &Brokers,
+    state_topic: &StateTopic,
+    value: &TeleinfoFrame,
+    retries: u32,
+    pretty_json: bool,
+) -> Result<(), ClientError> {
     event!(Level::INFO, "Publishing teleinfo frame to MQTT");
 
-    client
-        .publish(format!("teleinfo/{}", value.adco), value.to_string())
+    let topic = state_topic.for_adco(&value.adco);
+    let payload = if pretty_json {
+        format!("{:#}", value)
+    } else {
+        value.to_string()
+    };
+    // Retry a few times so that a broker hiccup doesn't leave a gap in the energy data
+    let mut backoff = PUBLISH_RETRY_BACKOFF_INITIAL;
+    let mut attempt = 0;
+    loop {
+        match brokers.publish_state(topic.clone(), payload.clone()).await {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt < retries => {
+                attempt += 1;
+                event!(
+                    Level::WARN,
+                    error = ?e,
+                    attempt,
+                    ?backoff,
+                    "Failed to publish teleinfo frame, retrying"
+                );
+                time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[instrument(skip(brokers))]
+pub async fn publish_last_frame_age(
+    brokers: &Brokers,
+    state_topic: &StateTopic,
+    adco: &str,
+    age: Duration,
+) -> Result<(), ClientError> {
+    brokers
+        .publish_state(
+            format!("{}/last_frame_age_seconds", state_topic.for_adco(adco)),
+            age.as_secs().to_string(),
+        )
+        .await
+}
+
+#[instrument(skip(brokers))]
+pub async fn publish_power_alert(
+    brokers: &Brokers,
+    state_topic: &StateTopic,
+    adco: &str,
+    active: bool,
+) -> Result<(), ClientError> {
+    brokers
+        .publish_state(
+            format!("{}/power_alert", state_topic.for_adco(adco)),
+            if active { "ON" } else { "OFF" }.to_string(),
+        )
+        .await
+}
+
+#[instrument(skip(brokers, config))]
+pub async fn publish_config(
+    brokers: &Brokers,
+    state_topic: &StateTopic,
+    adco: &str,
+    config: &str,
+) -> Result<(), ClientError> {
+    event!(Level::INFO, "Publishing effective configuration to MQTT");
+
+    brokers
+        .publish(
+            format!("{}/config", state_topic.for_adco(adco)),
+            config.to_string(),
+        )
         .await
 }
+
+// Domoticz devices are addressed by their idx, configured per device
+#[derive(Debug, Default)]
+pub struct DomoticzIdx {
+    pub p1: Option<u32>,   // "P1 Smart Meter" device: energy index + power
+    pub papp: Option<u32>, // "Usage (Electric)" device: power only
+}
+
+impl DomoticzIdx {
+    pub fn is_enabled(&self) -> bool {
+        self.p1.is_some() || self.papp.is_some()
+    }
+}
+
+#[instrument(skip(brokers, value))]
+pub async fn publish_domoticz(
+    brokers: &Brokers,
+    idx: &DomoticzIdx,
+    value: &TeleinfoFrame,
+) -> Result<(), ClientError> {
+    event!(Level::INFO, "Publishing teleinfo frame to Domoticz");
+
+    if let Some(idx) = idx.p1 {
+        match domoticz_p1_payload(idx, value) {
+            Some(payload) => {
+                brokers
+                    .publish_state(DOMOTICZ_TOPIC.to_string(), payload.to_string())
+                    .await?
+            }
+            None => event!(
+                Level::WARN,
+                base = value.base.as_deref(),
+                papp = value.papp,
+                "BASE or PAPP is missing or not a number, skipping the Domoticz P1 meter"
+            ),
+        }
+    }
+    if let Some(idx) = idx.papp {
+        match domoticz_papp_payload(idx, value) {
+            Some(payload) => {
+                brokers
+                    .publish_state(DOMOTICZ_TOPIC.to_string(), payload.to_string())
+                    .await?
+            }
+            None => event!(
+                Level::WARN,
+                papp = value.papp,
+                "PAPP is not a number, skipping the Domoticz power sensor"
+            ),
+        }
+    }
+    Ok(())
+}
+
+// None when the values are not numbers, Domoticz expects them without leading zeros
+fn domoticz_p1_payload(idx: u32, value: &TeleinfoFrame) -> Option<Value> {
+    let base = value.base.as_deref()?.parse::<i64>().ok()?;
+    let papp = value.papp.parse::<i32>().ok()?;
+    Some(json!({
+        "idx": idx,
+        "nvalue": 0,
+        "svalue": format!("{};0;0;0;{};0", base, papp),
+    }))
+}
+
+fn domoticz_papp_payload(idx: u32, value: &TeleinfoFrame) -> Option<Value> {
+    let papp = value.papp.parse::<i32>().ok()?;
+    Some(json!({"idx": idx, "nvalue": 0, "svalue": papp.to_string()}))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::teleinfo::parser::parse_teleinfo;
+
+    fn frame() -> TeleinfoFrame {
+        parse_teleinfo("ADCO 012345678901 E\nOPTARIF BASE 0\nISOUSC 30 9\nBASE 002809718 .\nPTEC TH.. $\nIINST 002 Y\nIMAX 090 H\nPAPP 00390 -\nHHPHC A ,\nMOTDETAT 000000 B", true).unwrap()
+    }
+
+    #[test]
+    fn test_domoticz_p1_payload() {
+        assert_eq!(
+            domoticz_p1_payload(42, &frame()).unwrap().to_string(),
+            r#"{"idx":42,"nvalue":0,"svalue":"2809718;0;0;0;390;0"}"#
+        );
+    }
+
+    #[test]
+    fn test_domoticz_payload_not_a_number() {
+        let mut frame = frame();
+        frame.papp = "0039A".to_string();
+        assert_eq!(domoticz_p1_payload(42, &frame), None);
+        assert_eq!(domoticz_papp_payload(7, &frame), None);
+    }
+
+    #[test]
+    fn test_domoticz_papp_payload() {
+        assert_eq!(
+            domoticz_papp_payload(7, &frame()).unwrap().to_string(),
+            r#"{"idx":7,"nvalue":0,"svalue":"390"}"#
+        );
+    }
+
+    #[test]
+    fn test_state_topic_default_template() {
+        let state_topic =
+            StateTopic::new(DEFAULT_STATE_TOPIC_TEMPLATE, DEFAULT_BASE_TOPIC, "pi").unwrap();
+        assert_eq!(
+            state_topic.for_adco("012345678901"),
+            "teleinfo/012345678901"
+        );
+    }
+
+    #[test]
+    fn test_state_topic_custom_template() {
+        let state_topic = StateTopic::new("{base}/{hostname}/{adco}/state", "home", "pi").unwrap();
+        assert_eq!(
+            state_topic.for_adco("012345678901"),
+            "home/pi/012345678901/state"
+        );
+    }
+
+    #[test]
+    fn test_state_topic_without_adco() {
+        let state_topic = StateTopic::new(DEFAULT_STATE_TOPIC_TEMPLATE, DEFAULT_BASE_TOPIC, "pi")
+            .unwrap()
+            .with_topic_id(TopicId::Aliases(HashMap::from([(
+                "012345678901".to_string(),
+                "garage".to_string(),
+            )])));
+        assert_eq!(state_topic.for_adco("012345678901"), "teleinfo/garage");
+
+        let state_topic = StateTopic::new(DEFAULT_STATE_TOPIC_TEMPLATE, DEFAULT_BASE_TOPIC, "pi")
+            .unwrap()
+            .with_topic_id(TopicId::AdcoHash);
+        assert_eq!(
+            state_topic.for_adco("012345678901"),
+            "teleinfo/734f089e42c9f155"
+        );
+    }
+
+    #[test]
+    fn test_state_topic_aliases_two_meters() {
+        let aliases = parse_topic_aliases("012345678901=garage, 012345678902=house").unwrap();
+        let state_topic = StateTopic::new(DEFAULT_STATE_TOPIC_TEMPLATE, DEFAULT_BASE_TOPIC, "pi")
+            .unwrap()
+            .with_topic_id(TopicId::Aliases(aliases));
+        assert_eq!(state_topic.for_adco("012345678901"), "teleinfo/garage");
+        assert_eq!(state_topic.for_adco("012345678902"), "teleinfo/house");
+        // A meter without an alias gets its own topic
+        assert_eq!(
+            state_topic.for_adco("012345678903"),
+            format!("teleinfo/{:016x}", fnv1a_hash("012345678903"))
+        );
+    }
+
+    #[test]
+    fn test_parse_topic_aliases() {
+        assert!(parse_topic_aliases("garage").is_err());
+        assert!(parse_topic_aliases("012345678901=").is_err());
+        assert!(parse_topic_aliases("012345678901=garage,012345678902=garage").is_err());
+    }
+
+    #[test]
+    fn test_state_topic_template_requires_adco() {
+        assert!(StateTopic::new("{base}/state", DEFAULT_BASE_TOPIC, "pi").is_err());
+    }
+
+    #[test]
+    fn test_parse_broker() {
+        assert_eq!(
+            parse_broker("192.168.1.42", 1883),
+            Ok(("192.168.1.42".to_string(), 1883))
+        );
+        assert_eq!(
+            parse_broker("broker.example.com:8883", 1883),
+            Ok(("broker.example.com".to_string(), 8883))
+        );
+        assert!(parse_broker("broker.example.com:mqtt", 1883).is_err());
+    }
+
+    #[test]
+    fn test_parse_broker_ipv6() {
+        assert_eq!(
+            parse_broker("2001:db8::1", 1883),
+            Ok(("2001:db8::1".to_string(), 1883))
+        );
+        assert_eq!(
+            parse_broker("[::1]:8883", 1883),
+            Ok(("::1".to_string(), 8883))
+        );
+        assert_eq!(parse_broker("[::1]", 1883), Ok(("::1".to_string(), 1883)));
+        assert!(parse_broker("[::1]:mqtt", 1883).is_err());
+        assert!(parse_broker("[::1", 1883).is_err());
+    }
+}
@@ -0,0 +1,172 @@
+use crate::teleinfo::parser::TeleinfoFrame;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tracing::{event, Level};
+
+pub const DEFAULT_DEBOUNCE: Duration = Duration::from_secs(30);
+
+struct MeterAlert {
+    above_since: Option<Instant>,
+    active: bool,
+}
+
+// A software ADPS: raised once PAPP has stayed above the threshold for the whole
+// debounce period, so that a short inrush doesn't trigger it, and cleared as soon as
+// PAPP is back under the threshold. Each meter has its own alert.
+pub struct PowerAlert {
+    threshold_va: u32,
+    debounce: Duration,
+    meters: HashMap<String, MeterAlert>, // By ADCO
+}
+
+impl PowerAlert {
+    pub fn new(threshold_va: u32, debounce: Duration) -> Self {
+        PowerAlert {
+            threshold_va,
+            debounce,
+            meters: HashMap::new(),
+        }
+    }
+
+    // Returns the new state of the meter's alert when it changed, and its initial
+    // state on the meter's first frame so that it isn't unknown until the first alert
+    pub fn check(&mut self, frame: &TeleinfoFrame, now: Instant) -> Option<bool> {
+        let papp = frame.papp.parse::<u32>().ok()?;
+
+        let first_frame = !self.meters.contains_key(&frame.adco);
+        let meter = self.meters.entry(frame.adco.clone()).or_insert(MeterAlert {
+            above_since: None,
+            active: false,
+        });
+        let active = if papp > self.threshold_va {
+            let above_since = *meter.above_since.get_or_insert(now);
+            now.duration_since(above_since) >= self.debounce
+        } else {
+            meter.above_since = None;
+            false
+        };
+        if first_frame {
+            meter.active = active;
+            return Some(active);
+        }
+        if active == meter.active {
+            return None;
+        }
+
+        event!(
+            Level::WARN,
+            adco = frame.adco,
+            papp,
+            threshold_va = self.threshold_va,
+            active,
+            "Power alert changed"
+        );
+        meter.active = active;
+        Some(active)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::teleinfo::parser::parse_teleinfo;
+
+    fn frame(papp: &str) -> TeleinfoFrame {
+        meter_frame("012345678901", papp)
+    }
+
+    fn meter_frame(adco: &str, papp: &str) -> TeleinfoFrame {
+        let mut frame = parse_teleinfo("ADCO 012345678901 E\nOPTARIF BASE 0\nISOUSC 30 9\nBASE 002809718 .\nPTEC TH.. $\nIINST 002 Y\nIMAX 090 H\nPAPP 00390 -\nHHPHC A ,\nMOTDETAT 000000 B", true).unwrap();
+        frame.adco = adco.to_string();
+        frame.papp = papp.to_string();
+        frame
+    }
+
+    #[test]
+    fn test_power_alert_debounce() {
+        let mut alert = PowerAlert::new(6000, Duration::from_secs(10));
+        let start = Instant::now();
+
+        // The initial state is published
+        assert_eq!(alert.check(&frame("00390"), start), Some(false));
+        assert_eq!(alert.check(&frame("06500"), start), None);
+        assert_eq!(
+            alert.check(&frame("06500"), start + Duration::from_secs(5)),
+            None
+        );
+        assert_eq!(
+            alert.check(&frame("06500"), start + Duration::from_secs(10)),
+            Some(true)
+        );
+        assert_eq!(
+            alert.check(&frame("06500"), start + Duration::from_secs(11)),
+            None
+        );
+        assert_eq!(
+            alert.check(&frame("00390"), start + Duration::from_secs(12)),
+            Some(false)
+        );
+    }
+
+    #[test]
+    fn test_power_alert_short_peak() {
+        let mut alert = PowerAlert::new(6000, Duration::from_secs(10));
+        let start = Instant::now();
+
+        assert_eq!(alert.check(&frame("06500"), start), Some(false));
+        assert_eq!(
+            alert.check(&frame("00390"), start + Duration::from_secs(5)),
+            None
+        );
+        // The debounce period starts over with the next peak
+        assert_eq!(
+            alert.check(&frame("06500"), start + Duration::from_secs(12)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_power_alert_per_meter() {
+        let mut alert = PowerAlert::new(6000, Duration::from_secs(10));
+        let start = Instant::now();
+
+        assert_eq!(
+            alert.check(&meter_frame("012345678901", "06500"), start),
+            Some(false)
+        );
+        assert_eq!(
+            alert.check(&meter_frame("012345678902", "00390"), start),
+            Some(false)
+        );
+        // The other meter's low PAPP doesn't restart this meter's debounce period
+        assert_eq!(
+            alert.check(
+                &meter_frame("012345678902", "00390"),
+                start + Duration::from_secs(5)
+            ),
+            None
+        );
+        assert_eq!(
+            alert.check(
+                &meter_frame("012345678901", "06500"),
+                start + Duration::from_secs(10)
+            ),
+            Some(true)
+        );
+        // Nor clears its alert
+        assert_eq!(
+            alert.check(
+                &meter_frame("012345678902", "00390"),
+                start + Duration::from_secs(11)
+            ),
+            None
+        );
+        assert_eq!(
+            alert.check(
+                &meter_frame("012345678901", "06500"),
+                start + Duration::from_secs(12)
+            ),
+            None
+        );
+    }
+}
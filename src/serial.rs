@@ -1,38 +1,609 @@
+use crate::log_limit::LogLimiter;
 use async_stream::stream;
-use futures_util::stream::Stream;
+use futures_util::future;
+use futures_util::stream::{Stream, StreamExt};
 use rppal::uart::{Parity, Uart};
-use std::time::Duration;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 use tracing::{event, instrument, Level};
 
+// Historical TeleInfo mode
+pub const BAUD_RATE: u32 = 1200;
+// Standard TeleInfo mode
+const STANDARD_BAUD_RATE: u32 = 9600;
+
+const DEVICE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// The UART read timeout is set in tenths of a second, up to 25.5 seconds
+pub const MAX_READ_TIMEOUT: Duration = Duration::from_millis(25_500);
+
+// USB adapters (and their /dev/serial/by-id/ symlinks) may not be enumerated yet
+// when we start at boot, so wait for the device to show up instead of failing
+fn wait_for_device(serial_device: &str, timeout: Option<Duration>, log_interval: Duration) {
+    let start = Instant::now();
+    let mut log_limiter = LogLimiter::new(log_interval);
+    while !Path::new(serial_device).exists() {
+        if timeout.is_some_and(|timeout| start.elapsed() >= timeout) {
+            panic!("Serial device {} did not appear in time", serial_device);
+        }
+        if let Some(suppressed) = log_limiter.check() {
+            event!(
+                Level::WARN,
+                serial_device,
+                suppressed,
+                "Serial device not found, waiting for it to appear"
+            );
+        }
+        thread::sleep(DEVICE_POLL_INTERVAL);
+    }
+}
+
+// "-" or "stdin" reads raw TeleInfo from standard input instead of a serial port,
+// e.g. to pipe in a capture or a socat stream
+fn is_stdin(serial_device: &str) -> bool {
+    serial_device == "-" || serial_device == "stdin"
+}
+
+// What to do at the end of a capture file used as the serial port
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EofBehavior {
+    Loop,
+    Exit,
+    Idle,
+}
+
+impl FromStr for EofBehavior {
+    type Err = String;
+
+    fn from_str(behavior: &str) -> Result<Self, Self::Err> {
+        match behavior.to_lowercase().as_str() {
+            "loop" => Ok(EofBehavior::Loop),
+            "exit" => Ok(EofBehavior::Exit),
+            "idle" => Ok(EofBehavior::Idle),
+            _ => Err(format!("Unknown EOF behavior {:?}", behavior)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SerialOptions {
+    pub device: String,
+    pub wait_timeout: Option<Duration>,
+    pub auto_baud_timeout: Option<Duration>,
+    pub read_timeout: Option<Duration>,
+    pub mask_parity: bool,
+    pub log_interval: Duration,
+    pub at_eof: EofBehavior,
+    // The UART's current baud rate, which the baud detection may switch
+    pub baud_rate: Arc<AtomicU32>,
+    // Set by the parser once a frame with valid checksums came through, which stops
+    // the baud detection
+    pub frame_received: Arc<AtomicBool>,
+}
+
 #[instrument]
-pub fn serial_stream(serial_device: String) -> impl Stream<Item = Vec<u8>> {
-    let baud_rate = 1200;
+pub fn serial_stream(options: SerialOptions) -> impl Stream<Item = io::Result<Vec<u8>>> {
+    let reader: Box<dyn Read + Send> = if is_stdin(&options.device) {
+        event!(Level::INFO, "Reading from standard input");
+        with_parity_mask(io::stdin(), options.mask_parity)
+    } else if Path::new(&options.device).is_file() {
+        // A capture replayed as if it came from the meter, e.g. for testing
+        let file = File::open(&options.device).expect("Failed to open capture file");
+        event!(Level::INFO, path = options.device, at_eof = ?options.at_eof, "Replaying capture file");
+        with_parity_mask(
+            ReplayReader {
+                reader: file,
+                at_eof: options.at_eof,
+                read_any: false,
+            },
+            options.mask_parity,
+        )
+    } else {
+        // The UART masks the parity bit itself, before its baud detection looks at
+        // the bytes
+        Box::new(open_uart(options))
+    };
+
+    read_stream(reader)
+}
+
+fn with_parity_mask<R: Read + Send + 'static>(
+    reader: R,
+    mask_parity: bool,
+) -> Box<dyn Read + Send> {
+    if mask_parity {
+        Box::new(ParityMask(reader))
+    } else {
+        Box::new(reader)
+    }
+}
+
+fn open_uart(options: SerialOptions) -> UartReader<Uart> {
+    wait_for_device(&options.device, options.wait_timeout, options.log_interval);
+
+    let uart =
+        Uart::open(&options.device, BAUD_RATE, options.read_timeout).expect("Failed to open UART");
+    options.baud_rate.store(BAUD_RATE, Ordering::Relaxed);
+
+    UartReader {
+        device: options.device,
+        read_timeout: options.read_timeout,
+        mask_parity: options.mask_parity,
+        log_interval: options.log_interval,
+        uart,
+        baud_detector: options
+            .auto_baud_timeout
+            .map(|timeout| BaudDetector::new(timeout, options.frame_received)),
+        baud_rate: options.baud_rate,
+    }
+}
+
+fn open_uart_device(
+    serial_device: &str,
+    baud_rate: u32,
+    read_timeout: Option<Duration>,
+) -> rppal::uart::Result<Uart> {
     let data_bits = 7;
     let parity = Parity::None;
     let stop_bits = 1;
 
-    let mut uart_device = Uart::with_path(serial_device, baud_rate, parity, data_bits, stop_bits)
-        .expect("Failed to open UART");
-    uart_device
-        .set_read_mode(1, Duration::default())
-        .expect("Failed to set read mode");
+    let mut uart_device = Uart::with_path(serial_device, baud_rate, parity, data_bits, stop_bits)?;
+    // By default a read blocks until a byte is received. With a timeout it may
+    // return without any, which UartReader turns into a TimedOut error.
+    match read_timeout {
+        Some(timeout) => uart_device.set_read_mode(0, timeout)?,
+        None => uart_device.set_read_mode(1, Duration::default())?,
+    }
 
     event!(Level::INFO, ?uart_device, "Opened UART device");
 
-    let mut buffer = [0u8; 1];
-    stream! {
+    Ok(uart_device)
+}
+
+// Replays a raw capture of the serial port, e.g. recorded with `picocom --logfile`
+#[instrument]
+pub fn file_stream(path: PathBuf) -> impl Stream<Item = Vec<u8>> {
+    let file = File::open(&path).expect("Failed to open capture file");
+
+    event!(Level::INFO, ?path, "Opened capture file");
+
+    // A read error is logged by read_stream, and ends the capture like EOF
+    read_stream(file).filter_map(|bytes| future::ready(bytes.ok()))
+}
+
+// Detects when bytes keep flowing without ever forming a frame, which means the
+// baud rate doesn't match the meter's TeleInfo mode. Random bytes read at the wrong
+// baud rate easily contain STX and ETX, so only a frame whose data sets all have a
+// valid checksum locks the baud rate, even when it can't be parsed, e.g. a standard
+// mode frame.
+struct BaudDetector {
+    timeout: Duration,
+    frame_received: Arc<AtomicBool>,
+    since: Instant,
+    garbage_bytes: u64,
+}
+
+impl BaudDetector {
+    fn new(timeout: Duration, frame_received: Arc<AtomicBool>) -> Self {
+        BaudDetector {
+            timeout,
+            frame_received,
+            since: Instant::now(),
+            garbage_bytes: 0,
+        }
+    }
+
+    // Returns the number of bytes received without a frame when the baud rate
+    // should be switched
+    fn observe(&mut self, bytes: &[u8]) -> Option<u64> {
+        if self.frame_received.load(Ordering::Relaxed) {
+            return None;
+        }
+        self.garbage_bytes += bytes.len() as u64;
+
+        if self.since.elapsed() < self.timeout {
+            return None;
+        }
+        let garbage_bytes = self.garbage_bytes;
+        self.since = Instant::now();
+        self.garbage_bytes = 0;
+        Some(garbage_bytes)
+    }
+}
+
+// The UART operations UartReader relies on, so that it can be tested without a
+// serial port
+trait UartPort: Sized {
+    fn open(device: &str, baud_rate: u32, read_timeout: Option<Duration>) -> io::Result<Self>;
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+    fn baud_rate(&self) -> u32;
+    fn set_baud_rate(&mut self, baud_rate: u32) -> io::Result<()>;
+}
+
+impl UartPort for Uart {
+    fn open(device: &str, baud_rate: u32, read_timeout: Option<Duration>) -> io::Result<Self> {
+        open_uart_device(device, baud_rate, read_timeout).map_err(io::Error::other)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        Uart::read(self, buf).map_err(io::Error::other)
+    }
+
+    fn baud_rate(&self) -> u32 {
+        Uart::baud_rate(self)
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> io::Result<()> {
+        Uart::set_baud_rate(self, baud_rate).map_err(io::Error::other)
+    }
+}
+
+// rppal's Uart has its own read() rather than implementing std::io::Read
+struct UartReader<U> {
+    device: String,
+    read_timeout: Option<Duration>,
+    mask_parity: bool,
+    log_interval: Duration,
+    uart: U,
+    baud_detector: Option<BaudDetector>,
+    baud_rate: Arc<AtomicU32>,
+}
+
+impl<U: UartPort> Read for UartReader<U> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.uart.read(buf) {
+            // Bluetooth (rfcomm) and USB devices vanish when disconnected, wait for
+            // them to come back instead of giving up
+            Ok(0) | Err(_) if !Path::new(&self.device).exists() => {
+                self.reopen();
+                Err(io::ErrorKind::TimedOut.into())
+            }
+            // Nothing was received before the read mode's timeout, which isn't EOF
+            Ok(0) if self.read_timeout.is_some() => Err(io::ErrorKind::TimedOut.into()),
+            // Without a timeout a read blocks until a byte comes, so an empty read means
+            // the line hung up while the device stayed, e.g. a dropped rfcomm link, which
+            // may also fail reads with EIO. Reading again right away would spin.
+            Ok(0) | Err(_) => {
+                thread::sleep(DEVICE_POLL_INTERVAL);
+                self.reopen();
+                Err(io::ErrorKind::TimedOut.into())
+            }
+            Ok(bytes_read) => {
+                if self.mask_parity {
+                    mask_parity(&mut buf[..bytes_read]);
+                }
+                if let Some(garbage_bytes) = self
+                    .baud_detector
+                    .as_mut()
+                    .and_then(|baud_detector| baud_detector.observe(&buf[..bytes_read]))
+                {
+                    self.switch_baud_rate(garbage_bytes);
+                }
+                Ok(bytes_read)
+            }
+        }
+    }
+}
+
+impl<U: UartPort> UartReader<U> {
+    fn reopen(&mut self) {
+        event!(
+            Level::WARN,
+            device = self.device,
+            "Serial device disappeared or hung up, waiting for it to come back"
+        );
+        // Keep the baud rate, which may have been switched by the baud detection
+        let baud_rate = self.uart.baud_rate();
+        let mut log_limiter = LogLimiter::new(self.log_interval);
         loop {
-            match uart_device.read(&mut buffer) {
-                Ok(bytes_read) => {
-                    if bytes_read > 0 {
-                        yield buffer.to_vec();
+            wait_for_device(&self.device, None, self.log_interval);
+            match U::open(&self.device, baud_rate, self.read_timeout) {
+                Ok(uart) => {
+                    self.uart = uart;
+                    return;
+                }
+                Err(e) => {
+                    if let Some(suppressed) = log_limiter.check() {
+                        event!(Level::WARN, error = ?e, suppressed, "Failed to reopen UART, retrying");
                     }
+                    thread::sleep(DEVICE_POLL_INTERVAL);
                 }
+            }
+        }
+    }
+
+    fn switch_baud_rate(&mut self, garbage_bytes: u64) {
+        let baud_rate = match self.uart.baud_rate() {
+            BAUD_RATE => STANDARD_BAUD_RATE,
+            _ => BAUD_RATE,
+        };
+        event!(
+            Level::WARN,
+            garbage_bytes,
+            baud_rate,
+            "No frame received, switching baud rate"
+        );
+        match self.uart.set_baud_rate(baud_rate) {
+            Ok(_) => self.baud_rate.store(baud_rate, Ordering::Relaxed),
+            Err(e) => event!(Level::ERROR, error = ?e, baud_rate, "Failed to switch baud rate"),
+        }
+    }
+}
+
+struct ReplayReader<R> {
+    reader: R,
+    at_eof: EofBehavior,
+    // Whether anything was read since the last rewind
+    read_any: bool,
+}
+
+impl<R: Read + Seek> Read for ReplayReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.reader.read(buf)? {
+            0 => match self.at_eof {
+                EofBehavior::Exit => Ok(0),
+                // Looping over an empty capture would spin forever
+                EofBehavior::Loop if !self.read_any => Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Capture file is empty, nothing to loop over",
+                )),
+                EofBehavior::Loop => {
+                    self.reader.seek(SeekFrom::Start(0))?;
+                    self.read_any = false;
+                    Err(io::ErrorKind::TimedOut.into())
+                }
+                // Keeps the process (and its servers) alive without new data
+                EofBehavior::Idle => {
+                    thread::sleep(DEVICE_POLL_INTERVAL);
+                    Err(io::ErrorKind::TimedOut.into())
+                }
+            },
+            bytes_read => {
+                self.read_any = true;
+                Ok(bytes_read)
+            }
+        }
+    }
+}
+
+// Some USB adapters don't strip the 7E1 parity bit, which then shows up as the 8th
+// bit of every byte
+struct ParityMask<R>(R);
+
+impl<R: Read> Read for ParityMask<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes_read = self.0.read(buf)?;
+        mask_parity(&mut buf[..bytes_read]);
+        Ok(bytes_read)
+    }
+}
+
+fn mask_parity(bytes: &mut [u8]) {
+    for byte in bytes {
+        *byte &= 0x7F;
+    }
+}
+
+// Yields the bytes from any reader one at a time, until EOF or a read error, which is
+// yielded last so that it isn't mistaken for the end of the input
+fn read_stream<R: Read>(mut reader: R) -> impl Stream<Item = io::Result<Vec<u8>>> {
+    let mut buffer = [0u8; 1];
+    stream! {
+        loop {
+            match reader.read(&mut buffer) {
+                Ok(0) => break, // EOF
+                Ok(_) => yield Ok(buffer.to_vec()),
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
                 Err(e) => {
-                    event!(Level::ERROR, "Error reading from UART: {}", e);
+                    event!(Level::ERROR, "Error reading from serial source: {}", e);
+                    yield Err(e);
                     break;
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::io::Cursor;
+
+    async fn read_bytes<R: Read>(reader: R) -> Vec<Vec<u8>> {
+        read_stream(reader)
+            .map(|bytes| bytes.unwrap())
+            .collect::<Vec<_>>()
+            .await
+    }
+
+    // Fails with the given errors first, then reads from the inner reader
+    struct FlakyReader<R> {
+        errors: Vec<io::ErrorKind>,
+        inner: R,
+    }
+
+    impl<R: Read> Read for FlakyReader<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.errors.pop() {
+                Some(kind) => Err(kind.into()),
+                None => self.inner.read(buf),
+            }
+        }
+    }
+
+    // Never receives anything, and counts how many times it was opened
+    struct HungUpUart {
+        opens: Arc<AtomicU32>,
+    }
+
+    thread_local! {
+        static HUNG_UP_UART_OPENS: Arc<AtomicU32> = Arc::default();
+        // Whether reads fail with EIO rather than return nothing
+        static HUNG_UP_UART_EIO: Cell<bool> = const { Cell::new(false) };
+    }
+
+    impl UartPort for HungUpUart {
+        fn open(_: &str, _: u32, _: Option<Duration>) -> io::Result<Self> {
+            let opens = HUNG_UP_UART_OPENS.with(|opens| opens.clone());
+            opens.fetch_add(1, Ordering::Relaxed);
+            Ok(HungUpUart { opens })
+        }
+
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            if HUNG_UP_UART_EIO.get() {
+                // EIO
+                Err(io::Error::from_raw_os_error(5))
+            } else {
+                Ok(0)
+            }
+        }
+
+        fn baud_rate(&self) -> u32 {
+            BAUD_RATE
+        }
+
+        fn set_baud_rate(&mut self, _: u32) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn hung_up_uart_reader(read_timeout: Option<Duration>) -> UartReader<HungUpUart> {
+        UartReader {
+            // Exists, so the device is never considered gone
+            device: env!("CARGO_MANIFEST_DIR").to_string(),
+            read_timeout,
+            mask_parity: false,
+            log_interval: Duration::from_secs(60),
+            uart: HungUpUart::open("", BAUD_RATE, read_timeout).unwrap(),
+            baud_detector: None,
+            baud_rate: Arc::default(),
+        }
+    }
+
+    #[test]
+    fn test_uart_reader_hung_up() {
+        let mut reader = hung_up_uart_reader(None);
+        let start = Instant::now();
+        for _ in 0..2 {
+            let e = reader.read(&mut [0u8; 1]).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        }
+        // Backed off and reopened rather than spinning
+        assert!(start.elapsed() >= DEVICE_POLL_INTERVAL * 2);
+        assert_eq!(reader.uart.opens.load(Ordering::Relaxed), 3);
+
+        // A link that dropped may fail reads instead, the device staying
+        HUNG_UP_UART_EIO.set(true);
+        let e = reader.read(&mut [0u8; 1]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(reader.uart.opens.load(Ordering::Relaxed), 4);
+    }
+
+    #[test]
+    fn test_uart_reader_read_timeout() {
+        let mut reader = hung_up_uart_reader(Some(Duration::from_millis(100)));
+        for _ in 0..2 {
+            let e = reader.read(&mut [0u8; 1]).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        }
+        assert_eq!(reader.uart.opens.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_baud_detector() {
+        let frame_received = Arc::new(AtomicBool::new(false));
+        let mut baud_detector = BaudDetector::new(Duration::ZERO, frame_received.clone());
+        assert_eq!(baud_detector.observe(b"garbage"), Some(7));
+        // Locked once the parser got a frame with valid checksums
+        frame_received.store(true, Ordering::Relaxed);
+        assert_eq!(baud_detector.observe(b"garbage"), None);
+
+        let mut baud_detector = BaudDetector::new(Duration::from_secs(60), Arc::default());
+        assert_eq!(baud_detector.observe(b"garbage"), None);
+    }
+
+    #[test]
+    fn test_baud_detector_ignores_delimiters_in_garbage() {
+        let mut baud_detector = BaudDetector::new(Duration::ZERO, Arc::default());
+        assert_eq!(baud_detector.observe(b"\x02\x8f\x13\x03"), Some(4));
+        assert_eq!(baud_detector.observe(b"\x02"), Some(1));
+        assert_eq!(baud_detector.observe(b"\x03"), Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_read_stream_yields_bytes_until_eof() {
+        let bytes = read_bytes(Cursor::new(vec![0x02, b'A', 0x03])).await;
+        assert_eq!(bytes, vec![vec![0x02], vec![b'A'], vec![0x03]]);
+    }
+
+    #[tokio::test]
+    async fn test_read_stream_masks_parity() {
+        let reader = ParityMask(Cursor::new(vec![0x82, b'A' | 0x80, 0x03]));
+        let bytes = read_bytes(reader).await;
+        assert_eq!(bytes, vec![vec![0x02], vec![b'A'], vec![0x03]]);
+    }
+
+    #[tokio::test]
+    async fn test_read_stream_replay_loop() {
+        let reader = ReplayReader {
+            reader: Cursor::new(vec![b'A', b'B']),
+            at_eof: EofBehavior::Loop,
+            read_any: false,
+        };
+        let bytes = read_stream(reader)
+            .map(|bytes| bytes.unwrap())
+            .take(5)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            bytes,
+            vec![vec![b'A'], vec![b'B'], vec![b'A'], vec![b'B'], vec![b'A']]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_stream_replay_loop_empty() {
+        let reader = ReplayReader {
+            reader: Cursor::new(vec![]),
+            at_eof: EofBehavior::Loop,
+            read_any: false,
+        };
+        let bytes = read_stream(reader).collect::<Vec<_>>().await;
+        assert_eq!(bytes.len(), 1);
+        assert_eq!(
+            bytes[0].as_ref().unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_stream_skips_timeouts() {
+        let reader = FlakyReader {
+            errors: vec![io::ErrorKind::TimedOut, io::ErrorKind::TimedOut],
+            inner: Cursor::new(vec![b'A']),
+        };
+        let bytes = read_bytes(reader).await;
+        assert_eq!(bytes, vec![vec![b'A']]);
+    }
+
+    #[tokio::test]
+    async fn test_read_stream_stops_on_error() {
+        let reader = FlakyReader {
+            errors: vec![io::ErrorKind::BrokenPipe],
+            inner: Cursor::new(vec![b'A']),
+        };
+        let bytes = read_stream(reader).collect::<Vec<_>>().await;
+        assert_eq!(bytes.len(), 1);
+        assert_eq!(
+            bytes[0].as_ref().unwrap_err().kind(),
+            io::ErrorKind::BrokenPipe
+        );
+    }
+}
@@ -0,0 +1,92 @@
+use crate::status::{Counters, Status};
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{self, UdpSocket};
+use tokio::time;
+use tracing::{event, instrument, Level};
+
+const PUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+// Pushes the status counters to a StatsD (or DogStatsD) server over UDP, as an
+// alternative to scraping /metrics
+#[instrument(skip(status))]
+pub async fn serve(addr: String, status: Arc<Status>) -> io::Result<()> {
+    let target = net::lookup_host(&addr)
+        .await?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "StatsD address did not resolve"))?;
+    // The local socket has to be of the same address family as the server
+    let local_addr = if target.is_ipv6() {
+        "[::]:0"
+    } else {
+        "0.0.0.0:0"
+    };
+    let socket = UdpSocket::bind(local_addr).await?;
+    socket.connect(target).await?;
+    event!(Level::INFO, addr, "Pushing metrics to StatsD");
+
+    tokio::spawn(async move {
+        let mut previous = status.counters();
+        let mut ticks = time::interval(PUSH_INTERVAL);
+        ticks.tick().await;
+        loop {
+            ticks.tick().await;
+            let counters = status.counters();
+            let metrics = to_statsd(&counters, &previous, status.papp());
+            previous = counters;
+
+            // UDP is fire and forget, a missing StatsD server only shows up as errors
+            // on later sends
+            if let Err(e) = socket.send(metrics.as_bytes()).await {
+                event!(Level::DEBUG, error = ?e, "Error pushing metrics to StatsD");
+            }
+        }
+    });
+
+    Ok(())
+}
+
+// StatsD counters are increments, so only what changed since the last push is sent
+fn to_statsd(counters: &Counters, previous: &Counters, papp: Option<i32>) -> String {
+    let mut metrics = format!(
+        "teleinfo.frames:{}|c\nteleinfo.dropped_frames:{}|c\nteleinfo.failed_publishes:{}|c\nteleinfo.noise_bytes:{}|c\nteleinfo.parse_errors:{}|c",
+        counters.frames - previous.frames,
+        counters.dropped_frames - previous.dropped_frames,
+        counters.failed_publishes - previous.failed_publishes,
+        counters.noise_bytes - previous.noise_bytes,
+        counters.parse_errors - previous.parse_errors,
+    );
+    if let Some(papp) = papp {
+        metrics.push_str(&format!("\nteleinfo.papp_va:{}|g", papp));
+    }
+    metrics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_statsd() {
+        let previous = Counters {
+            frames: 10,
+            dropped_frames: 0,
+            failed_publishes: 1,
+            noise_bytes: 0,
+            parse_errors: 1,
+        };
+        let counters = Counters {
+            frames: 20,
+            dropped_frames: 2,
+            failed_publishes: 1,
+            noise_bytes: 3,
+            parse_errors: 2,
+        };
+        assert_eq!(
+            to_statsd(&counters, &previous, Some(390)),
+            "teleinfo.frames:10|c\nteleinfo.dropped_frames:2|c\nteleinfo.failed_publishes:0|c\nteleinfo.noise_bytes:3|c\nteleinfo.parse_errors:1|c\nteleinfo.papp_va:390|g"
+        );
+        assert!(!to_statsd(&counters, &previous, None).contains("papp_va"));
+    }
+}
@@ -0,0 +1,380 @@
+use crate::teleinfo::parser::TeleinfoFrame;
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{event, instrument, Level};
+
+// Upper bounds of the PAPP histogram buckets, covering the usual 3 to 12 kVA
+// subscriptions
+const PAPP_BUCKETS_VA: [f64; 8] = [
+    250.0, 500.0, 1000.0, 2000.0, 3000.0, 6000.0, 9000.0, 12000.0,
+];
+// Parsing a frame takes microseconds, anything slower hints at a starved CPU
+const PARSE_DURATION_BUCKETS_S: [f64; 7] = [0.00001, 0.00005, 0.0001, 0.0005, 0.001, 0.005, 0.01];
+// Publishing includes the retries with their backoff
+const PUBLISH_DURATION_BUCKETS_S: [f64; 8] = [0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+struct Histogram {
+    bounds: &'static [f64],
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            buckets: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        if let Some(bucket) = self.bounds.iter().position(|bound| value <= *bound) {
+            self.buckets[bucket] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn to_metrics(&self, name: &str) -> String {
+        let mut metrics = format!("# TYPE {} histogram\n", name);
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&self.buckets) {
+            cumulative += count;
+            metrics.push_str(&format!(
+                "{}_bucket{{le=\"{}\"}} {}\n",
+                name, bound, cumulative
+            ));
+        }
+        metrics.push_str(&format!(
+            "{}_bucket{{le=\"+Inf\"}} {}\n{}_sum {}\n{}_count {}\n",
+            name, self.count, name, self.sum, name, self.count
+        ));
+        metrics
+    }
+}
+
+struct LastFrame {
+    adco: String,
+    papp: Option<i32>,
+    iinst: Option<i32>,
+    received_at: Instant,
+}
+
+// A snapshot of the counters, for pushing metrics
+pub struct Counters {
+    pub frames: u64,
+    pub dropped_frames: u64,
+    pub failed_publishes: u64,
+    pub noise_bytes: u64,
+    pub parse_errors: u64,
+}
+
+// Counters and last values shared between the main loop and the status server
+pub struct Status {
+    started_at: Instant,
+    frames: AtomicU64,
+    dropped_frames: AtomicU64,
+    failed_publishes: AtomicU64,
+    // Shared with the reader thread, which counts the bytes as it frames them
+    noise_bytes: Arc<AtomicU64>,
+    // Shared with the reader thread as well, which parses the frames
+    parse_errors: Arc<AtomicU64>,
+    mqtt_connected: AtomicBool,
+    last_frame: Mutex<Option<LastFrame>>,
+    papp_histogram: Mutex<Histogram>,
+    parse_duration_histogram: Mutex<Histogram>,
+    publish_duration_histogram: Mutex<Histogram>,
+}
+
+impl Status {
+    pub fn new() -> Self {
+        Status {
+            started_at: Instant::now(),
+            frames: AtomicU64::new(0),
+            dropped_frames: AtomicU64::new(0),
+            failed_publishes: AtomicU64::new(0),
+            noise_bytes: Arc::new(AtomicU64::new(0)),
+            parse_errors: Arc::new(AtomicU64::new(0)),
+            mqtt_connected: AtomicBool::new(false),
+            last_frame: Mutex::new(None),
+            papp_histogram: Mutex::new(Histogram::new(&PAPP_BUCKETS_VA)),
+            parse_duration_histogram: Mutex::new(Histogram::new(&PARSE_DURATION_BUCKETS_S)),
+            publish_duration_histogram: Mutex::new(Histogram::new(&PUBLISH_DURATION_BUCKETS_S)),
+        }
+    }
+
+    pub fn record_frame(&self, frame: &TeleinfoFrame) {
+        self.frames.fetch_add(1, Ordering::Relaxed);
+        if let Ok(papp) = frame.papp.parse::<u64>() {
+            self.papp_histogram.lock().unwrap().observe(papp as f64);
+        }
+        *self.last_frame.lock().unwrap() = Some(LastFrame {
+            adco: frame.adco.clone(),
+            papp: frame.papp.parse::<i32>().ok(),
+            iinst: frame.iinst.parse::<i32>().ok(),
+            received_at: Instant::now(),
+        });
+    }
+
+    pub fn record_parse_duration(&self, duration: Duration) {
+        self.parse_duration_histogram
+            .lock()
+            .unwrap()
+            .observe(duration.as_secs_f64());
+    }
+
+    pub fn record_publish_duration(&self, duration: Duration) {
+        self.publish_duration_histogram
+            .lock()
+            .unwrap()
+            .observe(duration.as_secs_f64());
+    }
+
+    pub fn record_dropped_frames(&self, dropped: u64) {
+        self.dropped_frames.fetch_add(dropped, Ordering::Relaxed);
+    }
+
+    pub fn record_failed_publish(&self) {
+        self.failed_publishes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn noise_bytes(&self) -> Arc<AtomicU64> {
+        self.noise_bytes.clone()
+    }
+
+    pub fn parse_errors(&self) -> Arc<AtomicU64> {
+        self.parse_errors.clone()
+    }
+
+    // We have no view on the MQTT connection itself, so the outcome of the last
+    // publish stands for it
+    pub fn set_mqtt_connected(&self, connected: bool) {
+        self.mqtt_connected.store(connected, Ordering::Relaxed);
+    }
+
+    pub fn counters(&self) -> Counters {
+        Counters {
+            frames: self.frames.load(Ordering::Relaxed),
+            dropped_frames: self.dropped_frames.load(Ordering::Relaxed),
+            failed_publishes: self.failed_publishes.load(Ordering::Relaxed),
+            noise_bytes: self.noise_bytes.load(Ordering::Relaxed),
+            parse_errors: self.parse_errors.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn papp(&self) -> Option<i32> {
+        self.last_frame
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|last_frame| last_frame.papp)
+    }
+
+    fn to_json(&self) -> Value {
+        let last_frame = self.last_frame.lock().unwrap().as_ref().map(|last_frame| {
+            json!({
+                "adco": last_frame.adco,
+                "papp": last_frame.papp,
+                "iinst": last_frame.iinst,
+                // Tenths of a second are enough to tell a stalled meter
+                "age_seconds": (last_frame.received_at.elapsed().as_secs_f64() * 10.0).round() / 10.0,
+            })
+        });
+
+        json!({
+            "uptime_seconds": self.started_at.elapsed().as_secs(),
+            "frames": self.frames.load(Ordering::Relaxed),
+            "dropped_frames": self.dropped_frames.load(Ordering::Relaxed),
+            "failed_publishes": self.failed_publishes.load(Ordering::Relaxed),
+            "noise_bytes": self.noise_bytes.load(Ordering::Relaxed),
+            "parse_errors": self.parse_errors.load(Ordering::Relaxed),
+            "mqtt_connected": self.mqtt_connected.load(Ordering::Relaxed),
+            "last_frame": last_frame,
+        })
+    }
+
+    // Prometheus text exposition format
+    fn to_metrics(&self) -> String {
+        let mut metrics = format!(
+            "# TYPE teleinfo_frames_total counter\nteleinfo_frames_total {}\n\
+             # TYPE teleinfo_dropped_frames_total counter\nteleinfo_dropped_frames_total {}\n\
+             # TYPE teleinfo_failed_publishes_total counter\nteleinfo_failed_publishes_total {}\n\
+             # TYPE teleinfo_noise_bytes_total counter\nteleinfo_noise_bytes_total {}\n\
+             # TYPE teleinfo_parse_errors_total counter\nteleinfo_parse_errors_total {}\n",
+            self.frames.load(Ordering::Relaxed),
+            self.dropped_frames.load(Ordering::Relaxed),
+            self.failed_publishes.load(Ordering::Relaxed),
+            self.noise_bytes.load(Ordering::Relaxed),
+            self.parse_errors.load(Ordering::Relaxed),
+        );
+
+        if let Some(papp) = self.papp() {
+            metrics.push_str(&format!(
+                "# TYPE teleinfo_papp_va gauge\nteleinfo_papp_va {}\n",
+                papp
+            ));
+        }
+
+        metrics.push_str(
+            &self
+                .papp_histogram
+                .lock()
+                .unwrap()
+                .to_metrics("teleinfo_papp_va_observed"),
+        );
+        metrics.push_str(
+            &self
+                .parse_duration_histogram
+                .lock()
+                .unwrap()
+                .to_metrics("teleinfo_parse_duration_seconds"),
+        );
+        metrics.push_str(
+            &self
+                .publish_duration_histogram
+                .lock()
+                .unwrap()
+                .to_metrics("teleinfo_publish_duration_seconds"),
+        );
+
+        metrics
+    }
+}
+
+// Minimal HTTP server answering GET /status with the JSON status, and GET /metrics
+// with Prometheus metrics
+#[instrument(skip(status))]
+pub async fn serve(addr: String, status: Arc<Status>) -> std::io::Result<()> {
+    let listener = TcpListener::bind(&addr).await?;
+    event!(Level::INFO, addr, "Serving status page");
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((socket, _)) => {
+                    tokio::spawn(handle_request(socket, status.clone()));
+                }
+                Err(e) => {
+                    event!(Level::ERROR, error = ?e, "Error accepting status client");
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
+
+async fn handle_request(mut socket: TcpStream, status: Arc<Status>) {
+    let mut buffer = [0u8; 1024];
+    let bytes_read = match socket.read(&mut buffer).await {
+        Ok(bytes_read) => bytes_read,
+        Err(e) => {
+            event!(Level::DEBUG, error = ?e, "Error reading status request");
+            return;
+        }
+    };
+
+    let request = String::from_utf8_lossy(&buffer[..bytes_read]);
+    let response = match request.lines().next() {
+        Some(request_line) if request_line.starts_with("GET /status ") => {
+            let body = status.to_json().to_string();
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        Some(request_line) if request_line.starts_with("GET /metrics ") => {
+            let body = status.to_metrics();
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+
+    if let Err(e) = socket.write_all(response.as_bytes()).await {
+        event!(Level::DEBUG, error = ?e, "Error writing status response");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::teleinfo::parser::parse_teleinfo;
+
+    #[test]
+    fn test_status_to_json() {
+        let status = Status::new();
+        assert_eq!(
+            status.to_json().to_string(),
+            r#"{"uptime_seconds":0,"frames":0,"dropped_frames":0,"failed_publishes":0,"noise_bytes":0,"parse_errors":0,"mqtt_connected":false,"last_frame":null}"#
+        );
+
+        let frame = parse_teleinfo("ADCO 012345678901 E\nOPTARIF BASE 0\nISOUSC 30 9\nBASE 002809718 .\nPTEC TH.. $\nIINST 002 Y\nIMAX 090 H\nPAPP 00390 -\nHHPHC A ,\nMOTDETAT 000000 B", true).unwrap();
+        status.record_frame(&frame);
+        status.set_mqtt_connected(true);
+        let json = status.to_json();
+        assert_eq!(json["frames"], 1);
+        assert_eq!(json["mqtt_connected"], true);
+        assert_eq!(
+            json["last_frame"],
+            json!({"adco": "012345678901", "papp": 390, "iinst": 2, "age_seconds": 0.0})
+        );
+    }
+
+    #[test]
+    fn test_status_to_json_escapes_adco() {
+        let status = Status::new();
+        let mut frame = parse_teleinfo("ADCO 012345678901 E\nOPTARIF BASE 0\nISOUSC 30 9\nBASE 002809718 .\nPTEC TH.. $\nIINST 002 Y\nIMAX 090 H\nPAPP 00390 -\nHHPHC A ,\nMOTDETAT 000000 B", true).unwrap();
+        frame.adco = r#"0123"5678\01"#.to_string();
+        status.record_frame(&frame);
+        let json: Value = serde_json::from_str(&status.to_json().to_string()).unwrap();
+        assert_eq!(json["last_frame"]["adco"], r#"0123"5678\01"#);
+    }
+
+    #[test]
+    fn test_status_to_metrics() {
+        let status = Status::new();
+        let frame = parse_teleinfo("ADCO 012345678901 E\nOPTARIF BASE 0\nISOUSC 30 9\nBASE 002809718 .\nPTEC TH.. $\nIINST 002 Y\nIMAX 090 H\nPAPP 00390 -\nHHPHC A ,\nMOTDETAT 000000 B", true).unwrap();
+        status.record_frame(&frame);
+
+        let metrics = status.to_metrics();
+        assert!(metrics.contains("teleinfo_frames_total 1\n"));
+        assert!(metrics.contains("teleinfo_noise_bytes_total 0\n"));
+        assert!(metrics.contains("teleinfo_parse_errors_total 0\n"));
+        assert!(metrics.contains("teleinfo_papp_va 390\n"));
+        assert!(metrics.contains("teleinfo_papp_va_observed_bucket{le=\"250\"} 0\n"));
+        assert!(metrics.contains("teleinfo_papp_va_observed_bucket{le=\"500\"} 1\n"));
+        assert!(metrics.contains("teleinfo_papp_va_observed_bucket{le=\"12000\"} 1\n"));
+        assert!(metrics.contains("teleinfo_papp_va_observed_bucket{le=\"+Inf\"} 1\n"));
+        assert!(metrics.contains("teleinfo_papp_va_observed_sum 390\n"));
+        assert!(metrics.contains("teleinfo_papp_va_observed_count 1\n"));
+    }
+
+    #[test]
+    fn test_status_to_metrics_durations() {
+        let status = Status::new();
+        status.record_parse_duration(Duration::from_micros(20));
+        status.record_publish_duration(Duration::from_millis(3));
+        status.record_publish_duration(Duration::from_secs(10));
+
+        let metrics = status.to_metrics();
+        assert!(metrics.contains("teleinfo_parse_duration_seconds_bucket{le=\"0.00001\"} 0\n"));
+        assert!(metrics.contains("teleinfo_parse_duration_seconds_bucket{le=\"0.00005\"} 1\n"));
+        assert!(metrics.contains("teleinfo_parse_duration_seconds_count 1\n"));
+        assert!(metrics.contains("teleinfo_publish_duration_seconds_bucket{le=\"0.005\"} 1\n"));
+        assert!(metrics.contains("teleinfo_publish_duration_seconds_bucket{le=\"5\"} 1\n"));
+        assert!(metrics.contains("teleinfo_publish_duration_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(metrics.contains("teleinfo_publish_duration_seconds_sum 10.003\n"));
+    }
+}
@@ -1,28 +1,35 @@
+use serde_json::{json, Value};
 use std::collections::HashMap;
 use std::error::Error;
 use std::fmt;
+use tracing::{event, Level};
+
+const STX: char = '\x02';
+const ETX: char = '\x03';
 
 // A teleinfo frame is a set of data sets
 // Each data set is a key-value pair + a checksum
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct TeleinfoFrame {
-    pub adco: String,     // Adresse du compteur
-    pub optarif: String,  // Option tarifaire
-    pub isousc: String,   // Intensité souscrite, en A
-    pub base: String,     // Index option base, en Wh
-    pub ptec: String,     // Période tarifaire en cours
-    pub iinst: String,    // Intensité instantanée, en A
-    pub imax: String,     // Intensité maximale appelée, en A
-    pub papp: String,     // Puissance apparente, en VA (arrondie à la dizaine la plus proche)
-    pub hhphc: String,    // Horaire Heures Pleines Heures Creuses
-    pub motdetat: String, // Mot d'état du compteur
+    pub adco: String,             // Adresse du compteur
+    pub optarif: String,          // Option tarifaire
+    pub isousc: String,           // Intensité souscrite, en A
+    pub base: Option<String>,     // Index option base, en Wh, None if dropped as implausible
+    pub ptec: String,             // Période tarifaire en cours
+    pub iinst: String,            // Intensité instantanée, en A
+    pub imax: String,             // Intensité maximale appelée, en A
+    pub papp: String, // Puissance apparente, en VA (arrondie à la dizaine la plus proche)
+    pub hhphc: String, // Horaire Heures Pleines Heures Creuses
+    pub motdetat: Option<String>, // Mot d'état du compteur, not sent by every meter
+    pub invalid_checksums: u32, // Data sets skipped for an invalid checksum
+    pub seq: Option<u64>, // Per-meter frame counter, set by the daemon when enabled
 }
 
 /*
 
 LABEL DATA CHECKSUM
 
-ADCO 012345678901 B
+ADCO 012345678901 E
 OPTARIF BASE 0
 ISOUSC 30 9
 BASE 002809718 .
@@ -46,88 +53,291 @@ impl PartialEq for TeleinfoFrame {
             && self.papp == other.papp
             && self.hhphc == other.hhphc
             && self.motdetat == other.motdetat
+            && self.invalid_checksums == other.invalid_checksums
+            && self.seq == other.seq
+    }
+}
+
+// Number of VA per subscribed ampere on a single-phase contract, i.e. a 30 A ISOUSC
+// is a 6 kVA subscription
+const VA_PER_SUBSCRIBED_AMPERE: u32 = 200;
+
+// The labels of the data sets kept in a TeleinfoFrame
+pub const LABELS: [&str; 10] = [
+    "ADCO", "OPTARIF", "ISOUSC", "BASE", "PTEC", "IINST", "IMAX", "PAPP", "HHPHC", "MOTDETAT",
+];
+
+impl TeleinfoFrame {
+    // Raw value of a data set by its label
+    pub fn field(&self, label: &str) -> Option<&str> {
+        match label {
+            "ADCO" => Some(&self.adco),
+            "OPTARIF" => Some(&self.optarif),
+            "ISOUSC" => Some(&self.isousc),
+            "BASE" => self.base.as_deref(),
+            "PTEC" => Some(&self.ptec),
+            "IINST" => Some(&self.iinst),
+            "IMAX" => Some(&self.imax),
+            "PAPP" => Some(&self.papp),
+            "HHPHC" => Some(&self.hhphc),
+            "MOTDETAT" => self.motdetat.as_deref(),
+            _ => None,
+        }
+    }
+
+    // Whether any of the given data sets differ, unlike PartialEq which compares
+    // whole frames including ever-changing fields such as the energy index
+    pub fn differs_in<S: AsRef<str>>(&self, other: &TeleinfoFrame, labels: &[S]) -> bool {
+        labels
+            .iter()
+            .any(|label| self.field(label.as_ref()) != other.field(label.as_ref()))
+    }
+
+    // Subscribed apparent power, derived from ISOUSC since historical mode doesn't
+    // provide it directly. None when ISOUSC is too large to be real, e.g. corrupted.
+    pub fn subscribed_power_va(&self) -> Option<u32> {
+        self.isousc
+            .parse::<u32>()
+            .ok()
+            .and_then(|isousc| isousc.checked_mul(VA_PER_SUBSCRIBED_AMPERE))
+    }
+
+    // Friendly name of the OPTARIF tariff option code
+    pub fn optarif_name(&self) -> Option<&'static str> {
+        match self.optarif.as_str() {
+            "BASE" => Some("Base"),
+            "HC.." => Some("Heures Creuses"),
+            "EJP." => Some("EJP"),
+            // The last character is the Tempo program the meter is set to
+            optarif if optarif.starts_with("BBR") => Some("Tempo"),
+            _ => None,
+        }
+    }
+}
+
+// Emits the value as a JSON number when it is one, and as a JSON string otherwise,
+// so that an unexpected value can't make the serialization panic
+fn json_number_or_string(raw: &str) -> Value {
+    match raw.parse::<i64>() {
+        Ok(number) => number.into(),
+        Err(_) => raw.into(),
     }
 }
 
-// Hijack the Display trait to provide a JSON representation of the TeleinfoFrame
-// that is compatible with Home Assistant's MQTT integration
+impl TeleinfoFrame {
+    // JSON representation of the frame that is compatible with Home Assistant's MQTT
+    // integration, with each data set's raw and interpreted values
+    pub fn to_json(&self) -> Value {
+        let mut json = json!({
+            "ADCO": {"raw": self.adco, "value": json_number_or_string(&self.adco)},
+            "OPTARIF": {
+                "raw": self.optarif,
+                "value": self.optarif,
+                "name": self.optarif_name(),
+            },
+            "ISOUSC": {"raw": self.isousc, "value": json_number_or_string(&self.isousc)},
+            "BASE": self.base.as_ref().map(|base| json!({
+                "raw": base,
+                "value": json_number_or_string(base),
+            })),
+            "PTEC": {"raw": self.ptec, "value": self.ptec.get(0..2).unwrap_or(&self.ptec)},
+            "IINST": {"raw": self.iinst, "value": json_number_or_string(&self.iinst)},
+            "IMAX": {"raw": self.imax, "value": json_number_or_string(&self.imax)},
+            "PAPP": {"raw": self.papp, "value": json_number_or_string(&self.papp)},
+            "HHPHC": {"raw": self.hhphc, "value": self.hhphc},
+            "SUBSCRIBED_POWER_VA": {"value": self.subscribed_power_va()},
+            "INVALID_CHECKSUMS": {"value": self.invalid_checksums},
+        });
+        // A dropped index is left out rather than published as null, which
+        // Home Assistant would record as an unknown reading
+        if self.base.is_none() {
+            if let Some(object) = json.as_object_mut() {
+                object.shift_remove("BASE");
+            }
+        }
+        if let Some(seq) = self.seq {
+            json["SEQ"] = json!({ "value": seq });
+        }
+        json
+    }
+}
+
+// Hijack the Display trait to provide the JSON representation of the TeleinfoFrame.
+// The JSON is compact, the alternate format ("{:#}") pretty-prints it for humans.
 impl fmt::Display for TeleinfoFrame {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            r#"{{
-"ADCO": {{"raw": "{}", "value": {}}},
-"OPTARIF": {{"raw": "{}", "value": "{}"}},
-"ISOUSC": {{"raw": "{}", "value": {}}},
-"BASE": {{"raw": "{}", "value": {}}},
-"PTEC": {{"raw": "{}", "value": "{}"}},
-"IINST": {{"raw": "{}", "value": {}}},
-"IMAX": {{"raw": "{}", "value": {}}},
-"PAPP": {{"raw": "{}", "value": {}}},
-"HHPHC": {{"raw": "{}", "value": "{}"}}
-}}"#,
-            self.adco,
-            self.adco.parse::<i64>().unwrap(),
-            self.optarif,
-            self.optarif,
-            self.isousc,
-            self.isousc.parse::<i32>().unwrap(),
-            self.base,
-            self.base.parse::<i64>().unwrap(),
-            self.ptec,
-            &self.ptec[0..2],
-            self.iinst,
-            self.iinst.parse::<i32>().unwrap(),
-            self.imax,
-            self.imax.parse::<i32>().unwrap(),
-            self.papp,
-            self.papp.parse::<i32>().unwrap(),
-            self.hhphc,
-            self.hhphc
-        )
-    }
-}
-
-pub fn parse_teleinfo(teleinfo: &str) -> Result<TeleinfoFrame, Box<dyn Error>> {
-    let mut teleinfo_map = HashMap::new();
-    for line in teleinfo.lines() {
-        let mut split = line.split_whitespace();
+        let json = if f.alternate() {
+            serde_json::to_string_pretty(&self.to_json())
+        } else {
+            serde_json::to_string(&self.to_json())
+        };
+        f.write_str(&json.map_err(|_| fmt::Error)?)
+    }
+}
 
-        let key = split.next().ok_or("Missing key")?;
-        if [0x02, 0x03].contains(&key.as_bytes()[0]) {
-            // Skip start and end of frame characters
+// The checksum is (S1 & 0x3F) + 0x20, S1 being the sum of the bytes from the label
+// to the separator before the checksum, which is excluded in historical mode (SP
+// separators) and included in standard mode (HT separators)
+pub fn validate_checksum(data_set: &str) -> bool {
+    let bytes = data_set.as_bytes();
+    if bytes.len() < 3 {
+        return false;
+    }
+
+    let checksum = bytes[bytes.len() - 1];
+    let summed_bytes = match bytes[bytes.len() - 2] {
+        b' ' => &bytes[..bytes.len() - 2],
+        b'\t' => &bytes[..bytes.len() - 1],
+        _ => return false,
+    };
+    let sum: u32 = summed_bytes.iter().map(|b| *b as u32).sum();
+
+    (sum & 0x3F) as u8 + 0x20 == checksum
+}
+
+// Splits a data set into its label and value on the same separator validate_checksum
+// relies on (the one before the checksum), so that both agree on the layout and a
+// tab-separated value may contain spaces
+fn split_data_set(data_set: &str) -> Option<(&str, &str)> {
+    let separator_index = data_set.len().checked_sub(2)?;
+    let separator = *data_set.as_bytes().get(separator_index)? as char;
+    if separator != ' ' && separator != '\t' {
+        return None;
+    }
+
+    // Some meters repeat the separator between the label and the value. The checksum
+    // covers the bytes as sent, extra separators included, but the value doesn't.
+    data_set
+        .get(..separator_index)?
+        .split_once(separator)
+        .map(|(label, value)| (label, value.trim_start_matches(separator)))
+}
+
+// The frame's data sets, without the start and end of frame characters and the data
+// sets' trailing CR. Spaces are kept since the checksum itself can be a space.
+fn data_sets(teleinfo: &str) -> impl Iterator<Item = &str> {
+    teleinfo
+        .lines()
+        .map(|line| line.trim_matches(|c| c == STX || c == ETX || c == '\r'))
+        .filter(|data_set| !data_set.is_empty())
+}
+
+// Standard mode separates the fields of a data set with HT, historical mode with SP
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TeleinfoMode {
+    Historical,
+    Standard,
+}
+
+// The mode of a frame whose data sets all have a valid checksum, which random bytes
+// read at the wrong baud rate don't have even when they contain STX and ETX
+pub fn frame_mode(teleinfo: &str) -> Option<TeleinfoMode> {
+    let mut mode = None;
+    for data_set in data_sets(teleinfo) {
+        if !validate_checksum(data_set) {
+            return None;
+        }
+        let data_set_mode = match data_set.as_bytes()[data_set.len() - 2] {
+            b'\t' => TeleinfoMode::Standard,
+            _ => TeleinfoMode::Historical,
+        };
+        if mode.is_some_and(|mode| mode != data_set_mode) {
+            return None;
+        }
+        mode = Some(data_set_mode);
+    }
+    mode
+}
+
+// The meter may send frames without any data set, e.g. while it resets
+pub fn is_empty_frame(teleinfo: &str) -> bool {
+    teleinfo
+        .chars()
+        .all(|c| c == STX || c == ETX || c.is_whitespace())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    InvalidChecksum(String),
+    MalformedDataSet(String),
+    MissingField(&'static str),
+    InvalidField { key: &'static str, value: String },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::InvalidChecksum(data_set) => {
+                write!(f, "Invalid checksum for data set {:?}", data_set)
+            }
+            ParseError::MalformedDataSet(data_set) => {
+                write!(f, "Malformed data set {:?}", data_set)
+            }
+            ParseError::MissingField(key) => write!(f, "Missing {}", key),
+            ParseError::InvalidField { key, value } => write!(f, "Invalid {} {:?}", key, value),
+        }
+    }
+}
+
+impl Error for ParseError {}
+
+// HHPHC is the peak hours schedule set by the utility, a single letter from A to Y
+fn is_valid_hhphc(hhphc: &str) -> bool {
+    matches!(hhphc.as_bytes(), [b'A'..=b'Y'])
+}
+
+// In strict mode a single data set with an invalid checksum rejects the whole frame,
+// otherwise only that data set is skipped
+pub fn parse_teleinfo(teleinfo: &str, strict: bool) -> Result<TeleinfoFrame, ParseError> {
+    let mut teleinfo_map = HashMap::new();
+    let mut invalid_checksums = 0;
+    for data_set in data_sets(teleinfo) {
+        if !validate_checksum(data_set) {
+            if strict {
+                return Err(ParseError::InvalidChecksum(data_set.to_string()));
+            }
+            event!(
+                Level::DEBUG,
+                data_set,
+                "Skipping data set with invalid checksum"
+            );
+            invalid_checksums += 1;
             continue;
         }
-        let value = split.next().ok_or("Missing value")?;
+
+        let (key, value) = split_data_set(data_set)
+            .ok_or_else(|| ParseError::MalformedDataSet(data_set.to_string()))?;
         teleinfo_map.insert(key, value);
     }
-    Ok(TeleinfoFrame {
-        adco: teleinfo_map.get("ADCO").ok_or("Missing ADCO")?.to_string(),
-        optarif: teleinfo_map
-            .get("OPTARIF")
-            .ok_or("Missing OPTARIF")?
-            .to_string(),
-        isousc: teleinfo_map
-            .get("ISOUSC")
-            .ok_or("Missing ISOUSC")?
-            .to_string(),
-        base: teleinfo_map.get("BASE").ok_or("Missing BASE")?.to_string(),
-        ptec: teleinfo_map.get("PTEC").ok_or("Missing PTEC")?.to_string(),
-        iinst: teleinfo_map
-            .get("IINST")
-            .ok_or("Missing IINST")?
-            .to_string(),
-        imax: teleinfo_map.get("IMAX").ok_or("Missing IMAX")?.to_string(),
-        papp: teleinfo_map.get("PAPP").ok_or("Missing PAPP")?.to_string(),
-        hhphc: teleinfo_map
-            .get("HHPHC")
-            .ok_or("Missing HHPHC")?
-            .to_string(),
-        motdetat: teleinfo_map
-            .get("MOTDETAT")
-            .ok_or("Missing MOTDETAT")?
-            .to_string(),
-    })
+    let field = |key: &'static str| {
+        teleinfo_map
+            .get(key)
+            .map(|value| value.to_string())
+            .ok_or(ParseError::MissingField(key))
+    };
+
+    let frame = TeleinfoFrame {
+        adco: field("ADCO")?,
+        optarif: field("OPTARIF")?,
+        isousc: field("ISOUSC")?,
+        base: Some(field("BASE")?),
+        ptec: field("PTEC")?,
+        iinst: field("IINST")?,
+        imax: field("IMAX")?,
+        papp: field("PAPP")?,
+        hhphc: field("HHPHC")?,
+        motdetat: teleinfo_map.get("MOTDETAT").map(|value| value.to_string()),
+        invalid_checksums,
+        seq: None,
+    };
+
+    if !is_valid_hhphc(&frame.hhphc) {
+        return Err(ParseError::InvalidField {
+            key: "HHPHC",
+            value: frame.hhphc,
+        });
+    }
+    Ok(frame)
 }
 
 #[cfg(test)]
@@ -136,19 +346,278 @@ mod tests {
 
     #[test]
     fn test_parse_teleinfo() {
-        let teleinfo = "ADCO 012345678901 B\nOPTARIF BASE 0\nISOUSC 30 9\nBASE 002809718 .\nPTEC TH.. $\nIINST 002 Y\nIMAX 090 H\nPAPP 00390 -\nHHPHC A ,\nMOTDETAT 000000 B";
-        let parse_teleinfo = parse_teleinfo(teleinfo);
+        let teleinfo = "ADCO 012345678901 E\nOPTARIF BASE 0\nISOUSC 30 9\nBASE 002809718 .\nPTEC TH.. $\nIINST 002 Y\nIMAX 090 H\nPAPP 00390 -\nHHPHC A ,\nMOTDETAT 000000 B";
+        let parse_teleinfo = parse_teleinfo(teleinfo, true);
         assert!(parse_teleinfo.is_ok());
         let parse_teleinfo = parse_teleinfo.unwrap();
         assert_eq!(parse_teleinfo.adco, "012345678901");
         assert_eq!(parse_teleinfo.optarif, "BASE");
         assert_eq!(parse_teleinfo.isousc, "30");
-        assert_eq!(parse_teleinfo.base, "002809718");
+        assert_eq!(parse_teleinfo.base.as_deref(), Some("002809718"));
         assert_eq!(parse_teleinfo.ptec, "TH..");
         assert_eq!(parse_teleinfo.iinst, "002");
         assert_eq!(parse_teleinfo.imax, "090");
         assert_eq!(parse_teleinfo.papp, "00390");
         assert_eq!(parse_teleinfo.hhphc, "A");
-        assert_eq!(parse_teleinfo.motdetat, "000000");
+        assert_eq!(parse_teleinfo.motdetat.as_deref(), Some("000000"));
+        assert_eq!(parse_teleinfo.subscribed_power_va(), Some(6000));
+    }
+
+    #[test]
+    fn test_optarif_name() {
+        let mut frame = parse_teleinfo("ADCO 012345678901 E\nOPTARIF BASE 0\nISOUSC 30 9\nBASE 002809718 .\nPTEC TH.. $\nIINST 002 Y\nIMAX 090 H\nPAPP 00390 -\nHHPHC A ,\nMOTDETAT 000000 B", true).unwrap();
+        assert_eq!(frame.optarif_name(), Some("Base"));
+        assert!(frame
+            .to_string()
+            .contains(r#""OPTARIF":{"raw":"BASE","value":"BASE","name":"Base"}"#));
+
+        frame.optarif = "HC..".to_string();
+        assert_eq!(frame.optarif_name(), Some("Heures Creuses"));
+        frame.optarif = "BBR(".to_string();
+        assert_eq!(frame.optarif_name(), Some("Tempo"));
+        frame.optarif = "XXXX".to_string();
+        assert_eq!(frame.optarif_name(), None);
+        assert!(frame.to_string().contains(r#""name":null"#));
+    }
+
+    #[test]
+    fn test_differs_in() {
+        let frame = parse_teleinfo("ADCO 012345678901 E\nOPTARIF BASE 0\nISOUSC 30 9\nBASE 002809718 .\nPTEC TH.. $\nIINST 002 Y\nIMAX 090 H\nPAPP 00390 -\nHHPHC A ,\nMOTDETAT 000000 B", true).unwrap();
+        let mut other = frame.clone();
+        other.base = Some("002809719".to_string());
+        assert_ne!(frame, other);
+        assert!(!frame.differs_in(&other, &["PAPP", "IINST", "PTEC"]));
+        assert!(frame.differs_in(&other, &["PAPP", "BASE"]));
+
+        other.papp = "00400".to_string();
+        assert!(frame.differs_in(&other, &["PAPP", "IINST", "PTEC"]));
+    }
+
+    #[test]
+    fn test_subscribed_power_va_non_numeric_isousc() {
+        let teleinfo = "ADCO 012345678901 E\nOPTARIF BASE 0\nISOUSC 3A J\nBASE 002809718 .\nPTEC TH.. $\nIINST 002 Y\nIMAX 090 H\nPAPP 00390 -\nHHPHC A ,\nMOTDETAT 000000 B";
+        let parse_teleinfo = parse_teleinfo(teleinfo, true).unwrap();
+        assert_eq!(parse_teleinfo.subscribed_power_va(), None);
+    }
+
+    #[test]
+    fn test_subscribed_power_va_oversized_isousc() {
+        let teleinfo = "ADCO 012345678901 E\nOPTARIF BASE 0\nISOUSC 99999999 ^\nBASE 002809718 .\nPTEC TH.. $\nIINST 002 Y\nIMAX 090 H\nPAPP 00390 -\nHHPHC A ,\nMOTDETAT 000000 B";
+        let parse_teleinfo = parse_teleinfo(teleinfo, true).unwrap();
+        assert_eq!(parse_teleinfo.subscribed_power_va(), None);
+        assert!(parse_teleinfo
+            .to_string()
+            .contains(r#""SUBSCRIBED_POWER_VA":{"value":null}"#));
+    }
+
+    #[test]
+    fn test_parse_teleinfo_without_motdetat() {
+        let teleinfo = "ADCO 012345678901 E\nOPTARIF BASE 0\nISOUSC 30 9\nBASE 002809718 .\nPTEC TH.. $\nIINST 002 Y\nIMAX 090 H\nPAPP 00390 -\nHHPHC A ,";
+        let parse_teleinfo = parse_teleinfo(teleinfo, true).unwrap();
+        assert_eq!(parse_teleinfo.papp, "00390");
+        assert_eq!(parse_teleinfo.motdetat, None);
+    }
+
+    #[test]
+    fn test_parse_teleinfo_invalid_hhphc() {
+        let teleinfo = "ADCO 012345678901 E\nOPTARIF BASE 0\nISOUSC 30 9\nBASE 002809718 .\nPTEC TH.. $\nIINST 002 Y\nIMAX 090 H\nPAPP 00390 -\nHHPHC Z E\nMOTDETAT 000000 B";
+        assert_eq!(
+            parse_teleinfo(teleinfo, true).unwrap_err(),
+            ParseError::InvalidField {
+                key: "HHPHC",
+                value: "Z".to_string()
+            }
+        );
+
+        let teleinfo = "ADCO 012345678901 E\nOPTARIF BASE 0\nISOUSC 30 9\nBASE 002809718 .\nPTEC TH.. $\nIINST 002 Y\nIMAX 090 H\nPAPP 00390 -\nMOTDETAT 000000 B";
+        assert_eq!(
+            parse_teleinfo(teleinfo, true).unwrap_err(),
+            ParseError::MissingField("HHPHC")
+        );
+    }
+
+    #[test]
+    fn test_validate_checksum() {
+        assert!(validate_checksum("ADCO 012345678901 E"));
+        assert!(validate_checksum("PAPP 00390 -"));
+        assert!(!validate_checksum("PAPP 00391 -"));
+        assert!(!validate_checksum("PAPP"));
+        // Standard mode includes the last separator in the checksum
+        assert!(validate_checksum("SINSTS\t00390\tR"));
+    }
+
+    #[test]
+    fn test_frame_mode() {
+        let historical = "\x02\nADCO 012345678901 E\r\nPAPP 00390 -\r\x03";
+        assert_eq!(frame_mode(historical), Some(TeleinfoMode::Historical));
+        let standard = "\x02\nADSC\t012345678901\t;\r\nSINSTS\t00390\tR\r\x03";
+        assert_eq!(frame_mode(standard), Some(TeleinfoMode::Standard));
+        assert_eq!(frame_mode("ADCO 012345678901 E\nPAPP 00391 -"), None);
+        assert_eq!(frame_mode("\x02\x0f\x13\x03"), None);
+        assert_eq!(frame_mode("\x02\n\r\x03"), None);
+    }
+
+    #[test]
+    fn test_parse_teleinfo_strict_rejects_invalid_checksum() {
+        let teleinfo = "\x02\nADCO 012345678901 E\r\nOPTARIF BASE 0\r\nISOUSC 30 9\r\nBASE 002809718 .\r\nPTEC TH.. $\r\nIINST 002 Y\r\nIMAX 090 H\r\nPAPP 00391 -\r\nHHPHC A ,\r\nMOTDETAT 000000 B\r\x03";
+        assert!(parse_teleinfo(teleinfo, true).is_err());
+    }
+
+    #[test]
+    fn test_parse_teleinfo_best_effort_skips_invalid_checksum() {
+        let teleinfo = "\x02\nADCO 012345678901 E\r\nOPTARIF BASE 0\r\nISOUSC 30 9\r\nBASE 002809718 .\r\nPTEC TH.. $\r\nIINST 002 Y\r\nIMAX 090 H\r\nPAPP 00390 -\r\nPAPP 00391 -\r\nHHPHC A ,\r\nMOTDETAT 000000 B\r\x03";
+        assert!(parse_teleinfo(teleinfo, true).is_err());
+        let parse_teleinfo = parse_teleinfo(teleinfo, false).unwrap();
+        assert_eq!(parse_teleinfo.papp, "00390");
+        assert_eq!(parse_teleinfo.invalid_checksums, 1);
+    }
+
+    #[test]
+    fn test_display_non_numeric_isousc() {
+        let teleinfo = "ADCO 012345678901 E\nOPTARIF BASE 0\nISOUSC 3A J\nBASE 002809718 .\nPTEC TH.. $\nIINST 002 Y\nIMAX 090 H\nPAPP 00390 -\nHHPHC A ,\nMOTDETAT 000000 B";
+        let parse_teleinfo = parse_teleinfo(teleinfo, true).unwrap();
+        assert!(parse_teleinfo
+            .to_string()
+            .contains(r#""ISOUSC":{"raw":"3A","value":"3A"}"#));
+    }
+
+    #[test]
+    fn test_display_non_numeric_fields() {
+        let teleinfo = "ADCO 012345678901 E\nOPTARIF BASE 0\nISOUSC 30 9\nBASE 002809718 .\nPTEC TH.. $\nIINST 002 Y\nIMAX 090 H\nPAPP 00390 -\nHHPHC A ,\nMOTDETAT 000000 B";
+        let mut parse_teleinfo = parse_teleinfo(teleinfo, true).unwrap();
+        parse_teleinfo.base = Some("0028O9718".to_string());
+        parse_teleinfo.papp = "".to_string();
+        parse_teleinfo.ptec = "T".to_string();
+        let json = parse_teleinfo.to_string();
+        assert!(json.contains(r#""BASE":{"raw":"0028O9718","value":"0028O9718"}"#));
+        assert!(json.contains(r#""PAPP":{"raw":"","value":""}"#));
+        assert!(json.contains(r#""PTEC":{"raw":"T","value":"T"}"#));
+    }
+
+    #[test]
+    fn test_display_dropped_base() {
+        let teleinfo = "ADCO 012345678901 E\nOPTARIF BASE 0\nISOUSC 30 9\nBASE 002809718 .\nPTEC TH.. $\nIINST 002 Y\nIMAX 090 H\nPAPP 00390 -\nHHPHC A ,\nMOTDETAT 000000 B";
+        let mut parse_teleinfo = parse_teleinfo(teleinfo, true).unwrap();
+        parse_teleinfo.base = None;
+        let json = parse_teleinfo.to_string();
+        assert!(!json.contains(r#""BASE":"#));
+        assert!(json.contains(r#""ISOUSC":{"raw":"30","value":30},"PTEC":"#));
+        assert!(json.contains(r#""PAPP":{"raw":"00390","value":390}"#));
+    }
+
+    #[test]
+    fn test_display_compact_and_pretty() {
+        let teleinfo = "ADCO 012345678901 E\nOPTARIF BASE 0\nISOUSC 30 9\nBASE 002809718 .\nPTEC TH.. $\nIINST 002 Y\nIMAX 090 H\nPAPP 00390 -\nHHPHC A ,\nMOTDETAT 000000 B";
+        let parse_teleinfo = parse_teleinfo(teleinfo, true).unwrap();
+        assert_eq!(
+            parse_teleinfo.to_string(),
+            r#"{"ADCO":{"raw":"012345678901","value":12345678901},"OPTARIF":{"raw":"BASE","value":"BASE","name":"Base"},"ISOUSC":{"raw":"30","value":30},"BASE":{"raw":"002809718","value":2809718},"PTEC":{"raw":"TH..","value":"TH"},"IINST":{"raw":"002","value":2},"IMAX":{"raw":"090","value":90},"PAPP":{"raw":"00390","value":390},"HHPHC":{"raw":"A","value":"A"},"SUBSCRIBED_POWER_VA":{"value":6000},"INVALID_CHECKSUMS":{"value":0}}"#
+        );
+        assert_eq!(
+            format!("{:#}", parse_teleinfo),
+            r#"{
+  "ADCO": {
+    "raw": "012345678901",
+    "value": 12345678901
+  },
+  "OPTARIF": {
+    "raw": "BASE",
+    "value": "BASE",
+    "name": "Base"
+  },
+  "ISOUSC": {
+    "raw": "30",
+    "value": 30
+  },
+  "BASE": {
+    "raw": "002809718",
+    "value": 2809718
+  },
+  "PTEC": {
+    "raw": "TH..",
+    "value": "TH"
+  },
+  "IINST": {
+    "raw": "002",
+    "value": 2
+  },
+  "IMAX": {
+    "raw": "090",
+    "value": 90
+  },
+  "PAPP": {
+    "raw": "00390",
+    "value": 390
+  },
+  "HHPHC": {
+    "raw": "A",
+    "value": "A"
+  },
+  "SUBSCRIBED_POWER_VA": {
+    "value": 6000
+  },
+  "INVALID_CHECKSUMS": {
+    "value": 0
+  }
+}"#
+        );
+    }
+
+    #[test]
+    fn test_display_escapes_values() {
+        let teleinfo = "ADCO 012345678901 E\nOPTARIF BASE 0\nISOUSC 30 9\nBASE 002809718 .\nPTEC TH.. $\nIINST 002 Y\nIMAX 090 H\nPAPP 00390 -\nHHPHC A ,\nMOTDETAT 000000 B";
+        let mut parse_teleinfo = parse_teleinfo(teleinfo, true).unwrap();
+        parse_teleinfo.optarif = r#"B"S\"#.to_string();
+        let json: Value = serde_json::from_str(&parse_teleinfo.to_string()).unwrap();
+        assert_eq!(json["OPTARIF"]["raw"], r#"B"S\"#);
+        let json: Value = serde_json::from_str(&format!("{:#}", parse_teleinfo)).unwrap();
+        assert_eq!(json["OPTARIF"]["value"], r#"B"S\"#);
+    }
+
+    #[test]
+    fn test_display_seq() {
+        let teleinfo = "ADCO 012345678901 E\nOPTARIF BASE 0\nISOUSC 30 9\nBASE 002809718 .\nPTEC TH.. $\nIINST 002 Y\nIMAX 090 H\nPAPP 00390 -\nHHPHC A ,\nMOTDETAT 000000 B";
+        let mut parse_teleinfo = parse_teleinfo(teleinfo, true).unwrap();
+        assert!(!parse_teleinfo.to_string().contains("SEQ"));
+        parse_teleinfo.seq = Some(42);
+        assert!(parse_teleinfo
+            .to_string()
+            .ends_with(r#""INVALID_CHECKSUMS":{"value":0},"SEQ":{"value":42}}"#));
+    }
+
+    #[test]
+    fn test_parse_teleinfo_tab_separated() {
+        let teleinfo = "ADCO\t012345678901\t7\nOPTARIF\tBASE\t\"\nISOUSC\t30\t+\nBASE\t002809718\t \nPTEC\tTH..\tV\nIINST\t002\tK\nIMAX\t090\t:\nPAPP\t00390\t_\nHHPHC\tA\t^\nMOTDETAT\t000000\t4";
+        let parse_teleinfo = parse_teleinfo(teleinfo, true).unwrap();
+        assert_eq!(parse_teleinfo.adco, "012345678901");
+        assert_eq!(parse_teleinfo.base.as_deref(), Some("002809718"));
+        assert_eq!(parse_teleinfo.ptec, "TH..");
+        assert_eq!(parse_teleinfo.papp, "00390");
+    }
+
+    #[test]
+    fn test_split_data_set() {
+        assert_eq!(split_data_set("PAPP 00390 -"), Some(("PAPP", "00390")));
+        assert_eq!(
+            split_data_set("PJOURF+1\t00008001 NONUTILE\t\\"),
+            Some(("PJOURF+1", "00008001 NONUTILE"))
+        );
+        assert_eq!(split_data_set("PAPP"), None);
+        assert_eq!(split_data_set("OPTARIF  BASE P"), Some(("OPTARIF", "BASE")));
+    }
+
+    #[test]
+    fn test_parse_teleinfo_double_space() {
+        assert!(validate_checksum("OPTARIF  BASE P"));
+        let teleinfo = "ADCO 012345678901 E\nOPTARIF  BASE P\nISOUSC 30 9\nBASE 002809718 .\nPTEC TH.. $\nIINST 002 Y\nIMAX 090 H\nPAPP 00390 -\nHHPHC A ,\nMOTDETAT 000000 B";
+        let parse_teleinfo = parse_teleinfo(teleinfo, true).unwrap();
+        assert_eq!(parse_teleinfo.optarif, "BASE");
+    }
+
+    #[test]
+    fn test_is_empty_frame() {
+        assert!(is_empty_frame("\x02\n\r\n\r\x03"));
+        assert!(is_empty_frame(""));
+        assert!(!is_empty_frame("\x02\nPAPP 00390 -\r\x03"));
     }
 }
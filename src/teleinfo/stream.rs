@@ -3,49 +3,178 @@ use super::parser::TeleinfoFrame;
 use async_stream::stream;
 use futures_util::stream::Stream;
 use futures_util::stream::StreamExt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tracing::event;
 use tracing::instrument;
 use tracing::Level;
 
-pub fn ascii_to_frames<S: Stream<Item = Vec<u8>>>(ascii_stream: S) -> impl Stream<Item = String> {
+// A historical frame is a few hundred bytes, so not seeing any STX for several frames'
+// worth of bytes means we're not reading TeleInfo, most likely because of a
+// historical/standard mode (1200/9600 baud) mismatch
+const MAX_BYTES_WITHOUT_STX: usize = 2048;
+
+const STX: u8 = 0x02;
+const ETX: u8 = 0x03;
+
+// The bytes delimiting a frame, STX and ETX unless an adapter wraps frames
+// differently
+#[derive(Debug, Clone, Copy)]
+pub struct Framing {
+    pub start: u8,
+    pub end: u8,
+}
+
+impl Default for Framing {
+    fn default() -> Self {
+        Framing {
+            start: STX,
+            end: ETX,
+        }
+    }
+}
+
+impl Framing {
+    // Custom delimiters are turned into STX and ETX, which is what the parser expects
+    fn normalize(&self, byte: u8) -> u8 {
+        if byte == self.start {
+            STX
+        } else if byte == self.end {
+            ETX
+        } else {
+            byte
+        }
+    }
+}
+
+// TeleInfo is 7-bit ASCII, so any byte above 0x7F is line noise (or an unmasked
+// parity bit). Those bytes are added to noise_bytes, and the frames they appear in are
+// dropped rather than left to fail their checksums.
+pub fn ascii_to_frames<S: Stream<Item = Vec<u8>>>(
+    ascii_stream: S,
+    framing: Framing,
+    noise_bytes: Arc<AtomicU64>,
+) -> impl Stream<Item = String> {
     let mut ascii_stream = Box::pin(ascii_stream);
     stream! {
         let mut teleinfo_buffer: Vec<Vec<u8>> = Vec::new();
+        let mut bytes_without_stx = 0;
+        let mut warned_without_stx = false;
+        let mut in_frame = false;
+        let mut frame_noise_bytes = 0;
         while let Some(value) = ascii_stream.next().await {
-            teleinfo_buffer.push(value.clone());
+            let value = value
+                .iter()
+                .map(|byte| framing.normalize(*byte))
+                .collect::<Vec<u8>>();
 
-            // A frame start with 0x02 and end with 0x03
-            if value == vec![0x03] {
-                if teleinfo_buffer.contains(&vec![0x02]) { // Only yield if we have a full frame
-                    yield teleinfo_buffer.
-                        iter().
-                        flat_map(|v| v.iter()).
-                        map(|b| *b as char).
-                        collect::<String>();
+            let value_noise_bytes = value.iter().filter(|byte| !byte.is_ascii()).count() as u64;
+            if value_noise_bytes > 0 {
+                noise_bytes.fetch_add(value_noise_bytes, Ordering::Relaxed);
+            }
+
+            if value == vec![STX] {
+                bytes_without_stx = 0;
+                warned_without_stx = false;
+                // Anything before the frame start, such as the end of a frame the
+                // adapter caught midway at startup, is discarded
+                in_frame = true;
+                frame_noise_bytes = 0;
+                teleinfo_buffer = Vec::new();
+            } else {
+                bytes_without_stx += value.len();
+                if bytes_without_stx >= MAX_BYTES_WITHOUT_STX && !warned_without_stx {
+                    warned_without_stx = true;
+                    event!(
+                        Level::WARN,
+                        bytes_without_stx,
+                        "No frame start found in the serial data, check that the meter's TeleInfo mode (historical or standard) matches the baud rate"
+                    );
                 }
+            }
+
+            if !in_frame {
+                continue;
+            }
+            // A frame start with 0x02 and end with 0x03
+            let end_of_frame = value == vec![ETX];
+            frame_noise_bytes += value_noise_bytes;
+            teleinfo_buffer.push(value);
 
+            if end_of_frame && frame_noise_bytes > 0 {
+                event!(Level::WARN, noise_bytes = frame_noise_bytes, "Dropping frame with non-ASCII bytes");
+                in_frame = false;
+                teleinfo_buffer = Vec::new();
+            } else if end_of_frame {
+                yield teleinfo_buffer.
+                    iter().
+                    flat_map(|v| v.iter()).
+                    map(|b| *b as char).
+                    collect::<String>();
 
                 // We reset the buffer for the next frame
+                in_frame = false;
                 teleinfo_buffer = Vec::new();
             }
         }
     }
 }
 
-#[instrument(skip(frame_stream))]
-pub fn frame_to_teleinfo<S: Stream<Item = String>>(
+// record_parse_duration is called with how long each frame took to parse, e.g. to
+// export it as a metric. parse_errors counts the frames that failed to parse, and
+// frame_received is set once a frame whose data sets all have a valid checksum came
+// through, parsed or not, e.g. to stop the baud detection.
+#[instrument(skip(frame_stream, record_parse_duration, parse_errors, frame_received))]
+pub fn frame_to_teleinfo<S: Stream<Item = String>, F: FnMut(Duration)>(
     frame_stream: S,
+    strict: bool,
+    mut record_parse_duration: F,
+    parse_errors: Arc<AtomicU64>,
+    frame_received: Arc<AtomicBool>,
 ) -> impl Stream<Item = TeleinfoFrame> {
     let mut frame_stream = Box::pin(frame_stream);
     stream! {
+        let mut last_frame_at: Option<Instant> = None;
         while let Some(value) = frame_stream.next().await {
-            let teleinfo = parser::parse_teleinfo(&value);
+            if parser::is_empty_frame(&value) {
+                event!(Level::DEBUG, "Skipping empty teleinfo frame");
+                continue;
+            }
+
+            let parse_start = Instant::now();
+            let teleinfo = parser::parse_teleinfo(&value, strict);
+            let parse_duration = parse_start.elapsed();
+            record_parse_duration(parse_duration);
+            event!(
+                Level::DEBUG,
+                ?parse_duration,
+                since_last_frame = ?last_frame_at.map(|at| parse_start.duration_since(at)),
+                "Parsed teleinfo frame"
+            );
+            last_frame_at = Some(parse_start);
+
             match teleinfo {
                 Ok(teleinfo) => {
+                    frame_received.store(true, Ordering::Relaxed);
+                    // Each field's raw and interpreted values
+                    event!(Level::DEBUG, frame = %teleinfo, "Parsed teleinfo fields");
                     yield teleinfo;
                 }
                 Err(e) => {
-                    event!(Level::ERROR, "Failed to parse teleinfo frame: {:?}", e);
+                    parse_errors.fetch_add(1, Ordering::Relaxed);
+                    event!(Level::ERROR, "Failed to parse teleinfo frame: {}", e);
+                    // The frame came at the right baud rate, but in a mode we can't parse
+                    if let Some(mode) = parser::frame_mode(&value) {
+                        if !frame_received.swap(true, Ordering::Relaxed)
+                            && mode == parser::TeleinfoMode::Standard
+                        {
+                            event!(
+                                Level::ERROR,
+                                "The meter sends TeleInfo standard mode frames, which are not supported, switch it to historical mode"
+                            );
+                        }
+                    }
                 }
             }
         }
@@ -56,26 +185,156 @@ pub fn frame_to_teleinfo<S: Stream<Item = String>>(
 mod tests {
     use super::*;
     use futures_util::stream::StreamExt;
+    use std::collections::HashMap;
+    use std::fmt;
+    use std::sync::Mutex;
+    use tracing::field::{Field, Visit};
+    use tracing::Subscriber;
+    use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+
+    // The level and fields of a logged event, to check what gets logged
+    #[derive(Debug)]
+    struct RecordedEvent {
+        level: Level,
+        fields: HashMap<String, String>,
+    }
+
+    impl Visit for RecordedEvent {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.fields
+                .insert(field.name().to_string(), format!("{:?}", value));
+        }
+    }
+
+    struct EventRecorder(Arc<Mutex<Vec<RecordedEvent>>>);
+
+    impl<S: Subscriber> Layer<S> for EventRecorder {
+        fn on_event(&self, event: &tracing::Event<'_>, _ctx: Context<'_, S>) {
+            let mut recorded = RecordedEvent {
+                level: *event.metadata().level(),
+                fields: HashMap::new(),
+            };
+            event.record(&mut recorded);
+            self.0.lock().unwrap().push(recorded);
+        }
+    }
+
+    async fn record_events_parsing(frame: &str) -> (Vec<TeleinfoFrame>, Vec<RecordedEvent>) {
+        record_events_parsing_frames(&[frame], Arc::default()).await
+    }
+
+    async fn record_events_parsing_frames(
+        frames: &[&str],
+        frame_received: Arc<AtomicBool>,
+    ) -> (Vec<TeleinfoFrame>, Vec<RecordedEvent>) {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let subscriber = tracing_subscriber::registry().with(EventRecorder(events.clone()));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let frame_stream = futures_util::stream::iter(frames.iter().map(|frame| frame.to_string()));
+        let teleinfo =
+            frame_to_teleinfo(frame_stream, true, |_| {}, Arc::default(), frame_received)
+                .collect::<Vec<_>>()
+                .await;
+        let events = std::mem::take(&mut *events.lock().unwrap());
+        (teleinfo, events)
+    }
+
+    async fn count_errors_parsing(frame: &str) -> usize {
+        let (teleinfo, events) = record_events_parsing(frame).await;
+        assert_eq!(teleinfo, vec![]);
+        events
+            .iter()
+            .filter(|event| event.level == Level::ERROR)
+            .count()
+    }
+
+    #[tokio::test]
+    async fn test_ascii_to_frames_custom_framing() {
+        let bytes = "[ADCO 012345678901 E]"
+            .bytes()
+            .map(|byte| vec![byte])
+            .collect::<Vec<_>>();
+        let framing = Framing {
+            start: b'[',
+            end: b']',
+        };
+        let frames = ascii_to_frames(
+            futures_util::stream::iter(bytes),
+            framing,
+            Arc::new(AtomicU64::new(0)),
+        )
+        .collect::<Vec<_>>()
+        .await;
+        assert_eq!(frames, vec!["\x02ADCO 012345678901 E\x03".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_ascii_to_frames_discards_until_first_stx() {
+        let bytes = "PP 00390 -\r\x03junk\x02\nADCO 012345678901 E\r\x03"
+            .bytes()
+            .map(|byte| vec![byte])
+            .collect::<Vec<_>>();
+        let frames = ascii_to_frames(
+            futures_util::stream::iter(bytes),
+            Framing::default(),
+            Arc::new(AtomicU64::new(0)),
+        )
+        .collect::<Vec<_>>()
+        .await;
+        assert_eq!(frames, vec!["\x02\nADCO 012345678901 E\r\x03".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_ascii_to_frames_drops_noise() {
+        let bytes = b"\x02\nADCO 0123\xc545678901 E\r\x03\x02\nADCO 012345678901 E\r\x03"
+            .iter()
+            .map(|byte| vec![*byte])
+            .collect::<Vec<_>>();
+        let noise_bytes = Arc::new(AtomicU64::new(0));
+        let frames = ascii_to_frames(
+            futures_util::stream::iter(bytes),
+            Framing::default(),
+            noise_bytes.clone(),
+        )
+        .collect::<Vec<_>>()
+        .await;
+        assert_eq!(frames, vec!["\x02\nADCO 012345678901 E\r\x03".to_string()]);
+        assert_eq!(noise_bytes.load(Ordering::Relaxed), 1);
+    }
 
     #[tokio::test]
     async fn test_frame_to_teleinfo() {
-        let frame = "ADCO 012345678901 B\nOPTARIF BASE 0\nISOUSC 30 9\nBASE 002809718 .\nPTEC TH.. $\nIINST 002 Y\nIMAX 090 H\nPAPP 00390 -\nHHPHC A ,\nMOTDETAT 000000 B";
+        let frame = "ADCO 012345678901 E\nOPTARIF BASE 0\nISOUSC 30 9\nBASE 002809718 .\nPTEC TH.. $\nIINST 002 Y\nIMAX 090 H\nPAPP 00390 -\nHHPHC A ,\nMOTDETAT 000000 B";
         let frame_stream = futures_util::stream::iter(vec![frame.to_string()]);
-        let teleinfo_stream = frame_to_teleinfo(frame_stream);
-        let teleinfo = teleinfo_stream.collect::<Vec<_>>().await;
+        let mut parse_durations = 0;
+        let frame_received = Arc::new(AtomicBool::new(false));
+        let teleinfo = frame_to_teleinfo(
+            frame_stream,
+            true,
+            |_| parse_durations += 1,
+            Arc::default(),
+            frame_received.clone(),
+        )
+        .collect::<Vec<_>>()
+        .await;
+        assert_eq!(parse_durations, 1);
+        assert!(frame_received.load(Ordering::Relaxed));
         assert_eq!(
             teleinfo,
             vec![TeleinfoFrame {
                 adco: "012345678901".to_string(),
                 optarif: "BASE".to_string(),
                 isousc: "30".to_string(),
-                base: "002809718".to_string(),
+                base: Some("002809718".to_string()),
                 ptec: "TH..".to_string(),
                 iinst: "002".to_string(),
                 imax: "090".to_string(),
                 papp: "00390".to_string(),
                 hhphc: "A".to_string(),
-                motdetat: "000000".to_string(),
+                motdetat: Some("000000".to_string()),
+                invalid_checksums: 0,
+                seq: None,
             }]
         );
     }
@@ -84,8 +343,58 @@ mod tests {
     async fn test_invalid_frame_to_teleinfo() {
         let frame = "invalid";
         let frame_stream = futures_util::stream::iter(vec![frame.to_string()]);
-        let teleinfo_stream = frame_to_teleinfo(frame_stream);
+        let parse_errors = Arc::new(AtomicU64::new(0));
+        let frame_received = Arc::new(AtomicBool::new(false));
+        let teleinfo_stream = frame_to_teleinfo(
+            frame_stream,
+            true,
+            |_| {},
+            parse_errors.clone(),
+            frame_received.clone(),
+        );
         let teleinfo = teleinfo_stream.collect::<Vec<_>>().await;
         assert_eq!(teleinfo, vec![]);
+        assert_eq!(parse_errors.load(Ordering::Relaxed), 1);
+        assert!(!frame_received.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn test_standard_frame_to_teleinfo() {
+        let frame = "\x02\nADSC\t012345678901\t;\r\nSINSTS\t00390\tR\r\x03";
+        let frame_received = Arc::new(AtomicBool::new(false));
+        let (teleinfo, events) =
+            record_events_parsing_frames(&[frame, frame], frame_received.clone()).await;
+        assert_eq!(teleinfo, vec![]);
+        // Came at the right baud rate, so the baud detection stops
+        assert!(frame_received.load(Ordering::Relaxed));
+        let unsupported = events
+            .iter()
+            .filter(|event| {
+                event
+                    .fields
+                    .get("message")
+                    .is_some_and(|message| message.contains("standard mode"))
+            })
+            .count();
+        assert_eq!(unsupported, 1);
+    }
+
+    #[tokio::test]
+    async fn test_frame_to_teleinfo_logs_fields() {
+        let frame = "ADCO 012345678901 E\nOPTARIF BASE 0\nISOUSC 30 9\nBASE 002809718 .\nPTEC TH.. $\nIINST 002 Y\nIMAX 090 H\nPAPP 00390 -\nHHPHC A ,\nMOTDETAT 000000 B";
+        let (_, events) = record_events_parsing(frame).await;
+        let logged_frame = events
+            .iter()
+            .find_map(|event| event.fields.get("frame"))
+            .unwrap();
+        assert!(logged_frame.contains(r#""PAPP":{"raw":"00390","value":390}"#));
+        assert!(logged_frame.contains(r#""PTEC":{"raw":"TH..","value":"TH"}"#));
+    }
+
+    #[tokio::test]
+    async fn test_empty_frame_to_teleinfo() {
+        // An empty frame is expected right after the meter starts, it isn't an error
+        assert_eq!(count_errors_parsing("\x02\n\r\n\r\x03").await, 0);
+        assert_eq!(count_errors_parsing("invalid").await, 1);
     }
 }
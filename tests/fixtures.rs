@@ -0,0 +1,116 @@
+use futures_util::stream::{self, StreamExt};
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use teleinfo2mqtt_rs::teleinfo::parser::{parse_teleinfo, ParseError, TeleinfoFrame};
+use teleinfo2mqtt_rs::teleinfo::stream::{ascii_to_frames, Framing};
+
+// Runs a raw capture of the serial port through the same framing and parsing as the
+// daemon, one byte at a time like the serial port reads them
+async fn parse_fixture(name: &str) -> Vec<Result<TeleinfoFrame, ParseError>> {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name);
+    let bytes = fs::read(&path).unwrap_or_else(|e| panic!("Failed to read {:?}: {}", path, e));
+
+    let noise_bytes = Arc::new(AtomicU64::new(0));
+    let frames = ascii_to_frames(
+        stream::iter(bytes.into_iter().map(|byte| vec![byte])),
+        Framing::default(),
+        noise_bytes.clone(),
+    )
+    .collect::<Vec<_>>()
+    .await;
+    assert_eq!(noise_bytes.load(Ordering::Relaxed), 0);
+
+    frames
+        .iter()
+        .map(|frame| parse_teleinfo(frame, true))
+        .collect()
+}
+
+#[tokio::test]
+async fn test_historical_base() {
+    // The capture starts in the middle of a frame, which is discarded
+    let frames = parse_fixture("historical_base.bin").await;
+    assert_eq!(frames.len(), 2);
+
+    let frames = frames
+        .into_iter()
+        .map(|frame| frame.unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(frames[0].adco, "012345678901");
+    assert_eq!(frames[0].base.as_deref(), Some("002809718"));
+    assert_eq!(frames[1].base.as_deref(), Some("002809719"));
+    assert_eq!(frames[1].papp, "00620");
+
+    let json = frames[1].to_string();
+    for key in ["ADCO", "OPTARIF", "BASE", "PTEC", "IINST", "PAPP", "HHPHC"] {
+        assert!(json.contains(&format!(r#""{}":"#, key)), "{} missing", key);
+    }
+}
+
+// While the subscribed power is exceeded, the meter adds ADPS and some meters drop
+// MOTDETAT, neither must prevent the frame from being published
+#[tokio::test]
+async fn test_historical_base_adps() {
+    let frames = parse_fixture("historical_base_adps.bin")
+        .await
+        .into_iter()
+        .map(|frame| frame.unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(frames.len(), 2);
+
+    assert_eq!(frames[0].adco, "012345678902");
+    assert_eq!(frames[0].isousc, "45");
+    assert_eq!(frames[0].iinst, "047");
+    assert_eq!(frames[0].papp, "10950");
+    assert_eq!(frames[0].motdetat, None);
+    assert_eq!(frames[1].base.as_deref(), Some("012345680"));
+    assert_eq!(frames[1].iinst, "046");
+
+    let json = frames[0].to_string();
+    assert!(json.contains(r#""PAPP":{"raw":"10950","value":10950}"#));
+    assert!(json.contains(r#""SUBSCRIBED_POWER_VA":{"value":9000}"#));
+}
+
+// A data set corrupted on the line fails its checksum, which only drops that frame
+#[tokio::test]
+async fn test_historical_base_checksum() {
+    let frames = parse_fixture("historical_base_checksum.bin").await;
+    assert_eq!(frames.len(), 3);
+
+    assert_eq!(
+        frames[0].as_ref().unwrap().base.as_deref(),
+        Some("000123456")
+    );
+    assert!(matches!(
+        &frames[1],
+        Err(ParseError::InvalidChecksum(data_set)) if data_set.starts_with("PAPP 00840")
+    ));
+    assert_eq!(
+        frames[2].as_ref().unwrap().base.as_deref(),
+        Some("000123458")
+    );
+    assert_eq!(frames[2].as_ref().unwrap().papp, "00250");
+}
+
+// Only the historical single-phase BASE option is supported so far, the other
+// captures must be rejected cleanly rather than panic or publish partial frames
+#[tokio::test]
+async fn test_unsupported_captures() {
+    for (name, missing_field) in [
+        ("historical_hc.bin", "BASE"),
+        ("historical_tempo.bin", "BASE"),
+        ("historical_triphase.bin", "IINST"),
+        ("standard.bin", "ADCO"),
+    ] {
+        assert_eq!(
+            parse_fixture(name).await,
+            vec![Err(ParseError::MissingField(missing_field))],
+            "{}",
+            name
+        );
+    }
+}